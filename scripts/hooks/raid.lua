@@ -0,0 +1,2 @@
+local event = ...
+return "gachiHYPER " .. (event.display_name or event.user) .. " is raiding with " .. (event.viewers or 0) .. " viewers!"
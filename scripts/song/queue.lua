@@ -1,17 +1,17 @@
 
+local args = util:get_args(...)
 local yt = api:youtube_playlist()
 if yt == nil then
     util:error("Youtube API unavailable!")
     return "FeelsDankMan something broke"
 end
-local se = api:streamelements()
+local se = api:streamelements(args.channel)
 if se == nil then
     util:error("StreamElements API unavailable!")
     return "FeelsDankMan something broke"
 end
 local sr = se:song_requests()
 
-local args = util:get_args(...)
 util:info(args)
 local playlist = args[0]
 if playlist == nil then
@@ -0,0 +1,2 @@
+local tick = ...
+return "peepoSip 👉 " .. tick.messages .. " messages since the last reminder, don't forget to hydrate"
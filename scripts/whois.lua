@@ -4,12 +4,10 @@ if se == nil then
     return "WAYTOODANK something broke"
 end
 
-local args = util:get_args(...)
+local ctx = ...
 
-local target = args[0]
-if target == nil then
-    return "WAYTOODANK something broke"
-end
+-- Defaults to the caller
+local target = ctx.args[1] or ctx.user
 
 util:info(target)
 
@@ -0,0 +1,156 @@
+//! The channels the bot is in. Seeded from `channels` in `bot.toml`, then persisted to a JSON list,
+//! so that the channels joined or left from chat survive restarts.
+use std::collections::{BTreeSet, HashSet};
+use std::sync::{Arc, Mutex};
+use twitchchat::Control;
+
+use super::config::channel_key;
+use crate::BackendError;
+
+#[derive(Debug)]
+pub struct Channels {
+    path: String,
+    joined: BTreeSet<String>,
+}
+
+/// The channel list, shared with the scripts.
+pub type SharedChannels = Arc<Mutex<Channels>>;
+
+impl Channels {
+    /// Loads the channel list from the given file, or uses the configured channels if it's missing.
+    pub fn load(path: &str, configured: &HashSet<String>) -> Result<Channels, BackendError> {
+        let joined = match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str::<Vec<String>>(&json).map_err(|e| {
+                BackendError::from(format!("Failed to parse the channels at {}: {}", path, e))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                configured.iter().cloned().collect()
+            }
+            Err(e) => {
+                return Err(BackendError::from(format!(
+                    "Failed to read the channels at {}: {}",
+                    path, e
+                )))
+            }
+        };
+        Ok(Channels {
+            path: path.to_owned(),
+            joined: joined.iter().map(|c| channel_key(c)).collect(),
+        })
+    }
+
+    fn save(&self) -> Result<(), BackendError> {
+        let json = serde_json::to_string_pretty(&self.joined)
+            .map_err(|e| BackendError::from(format!("Failed to serialize the channels: {}", e)))?;
+        std::fs::write(&self.path, json).map_err(|e| {
+            BackendError::from(format!(
+                "Failed to write the channels to {}: {}",
+                self.path, e
+            ))
+        })
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.joined.iter().cloned().collect()
+    }
+
+    pub fn contains(&self, channel: &str) -> bool {
+        self.joined.contains(&channel_key(channel))
+    }
+
+    /// Adds the channel and persists the change. Returns `false` if it was already there.
+    /// The list is left untouched if it can't be persisted.
+    pub fn insert(&mut self, channel: &str) -> Result<bool, BackendError> {
+        let channel = channel_key(channel);
+        if !self.joined.insert(channel.clone()) {
+            return Ok(false);
+        }
+        self.save().map(|_| true).map_err(|e| {
+            self.joined.remove(&channel);
+            e
+        })
+    }
+
+    /// Removes the channel and persists the change. Returns `false` if it wasn't there.
+    /// The list is left untouched if it can't be persisted.
+    pub fn remove(&mut self, channel: &str) -> Result<bool, BackendError> {
+        let channel = channel_key(channel);
+        if !self.joined.remove(&channel) {
+            return Ok(false);
+        }
+        self.save().map(|_| true).map_err(|e| {
+            self.joined.insert(channel);
+            e
+        })
+    }
+}
+
+/// Validates a channel name given in chat or by a script, returning it normalized.
+pub fn validate(channel: &str) -> Result<String, BackendError> {
+    let channel = channel_key(channel.trim_start_matches('@'));
+    let is_valid = (3..=25).contains(&channel.len())
+        && channel
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if is_valid {
+        Ok(channel)
+    } else {
+        Err(BackendError::from(format!("`{}` isn't a valid channel name", channel)))
+    }
+}
+
+/// Joins the channel and remembers it. Returns `false` if the bot is already there.
+/// The channel is only persisted once joined, and left again if that fails.
+pub async fn join(
+    control: &mut Control,
+    channels: &SharedChannels,
+    channel: &str,
+) -> Result<bool, BackendError> {
+    let channel = validate(channel)?;
+    if channels.lock().unwrap().contains(&channel) {
+        return Ok(false);
+    }
+    log::info!("Joining {}", channel);
+    control
+        .writer()
+        .join(&channel)
+        .await
+        .map_err(|e| BackendError::from(format!("Failed to join {}: {}", channel, e)))?;
+
+    let persisted = channels.lock().unwrap().insert(&channel);
+    if let Err(e) = persisted {
+        if let Err(e) = control.writer().part(&channel).await {
+            log::error!("Failed to leave {} again: {}", channel, e);
+        }
+        return Err(e);
+    }
+    Ok(true)
+}
+
+/// Leaves the channel and forgets it. Returns `false` if the bot wasn't there.
+/// The channel is only forgotten once left, and joined again if that fails.
+pub async fn part(
+    control: &mut Control,
+    channels: &SharedChannels,
+    channel: &str,
+) -> Result<bool, BackendError> {
+    let channel = validate(channel)?;
+    if !channels.lock().unwrap().contains(&channel) {
+        return Ok(false);
+    }
+    log::info!("Leaving {}", channel);
+    control
+        .writer()
+        .part(&channel)
+        .await
+        .map_err(|e| BackendError::from(format!("Failed to leave {}: {}", channel, e)))?;
+
+    let persisted = channels.lock().unwrap().remove(&channel);
+    if let Err(e) = persisted {
+        if let Err(e) = control.writer().join(&channel).await {
+            log::error!("Failed to join {} again: {}", channel, e);
+        }
+        return Err(e);
+    }
+    Ok(true)
+}
@@ -0,0 +1,580 @@
+//! CLI-style argument parsing for the commands that declare their arguments in `commands.json`:
+//!
+//! ```json
+//! "args": [
+//!     { "name": "playlist", "type": "url" },
+//!     { "name": "count", "type": "int", "optional": true, "default": "10" }
+//! ],
+//! "flags": [
+//!     { "name": "shuffle", "short": "s" },
+//!     { "name": "skip", "type": "duration" }
+//! ]
+//! ```
+//!
+//! Quoted strings (`"like this"`) are a single token. Flags without a type are boolean switches.
+use mlua::{Lua, ToLua};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArgKind {
+    String,
+    Int,
+    Url,
+    Username,
+    /// A duration like `90`, `90s`, `5m` or `1h30m`, coerced to seconds.
+    Duration,
+}
+
+impl Default for ArgKind {
+    fn default() -> Self {
+        ArgKind::String
+    }
+}
+
+impl std::fmt::Display for ArgKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ArgKind::String => "string",
+            ArgKind::Int => "int",
+            ArgKind::Url => "url",
+            ArgKind::Username => "username",
+            ArgKind::Duration => "duration",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArgSpec {
+    pub name: String,
+    #[serde(default, rename = "type")]
+    pub kind: ArgKind,
+    #[serde(default)]
+    pub optional: bool,
+    pub default: Option<String>,
+    /// Collects the rest of the message into this argument.
+    #[serde(default)]
+    pub rest: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FlagSpec {
+    pub name: String,
+    pub short: Option<char>,
+    /// The type of the flag's value. Flags without a type are boolean switches.
+    #[serde(rename = "type")]
+    pub kind: Option<ArgKind>,
+    pub default: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ArgSchema {
+    pub args: Vec<ArgSpec>,
+    pub flags: Vec<FlagSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArgValue {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl<'lua> ToLua<'lua> for ArgValue {
+    fn to_lua(self, lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
+        match self {
+            ArgValue::Str(s) => s.to_lua(lua),
+            ArgValue::Int(i) => i.to_lua(lua),
+            ArgValue::Bool(b) => b.to_lua(lua),
+        }
+    }
+}
+
+/// The validated and coerced arguments of an invocation.
+#[derive(Debug, Clone, Default)]
+pub struct ParsedArgs {
+    pub positional: Vec<(String, ArgValue)>,
+    pub flags: Vec<(String, ArgValue)>,
+}
+
+impl<'lua> ToLua<'lua> for ParsedArgs {
+    /// Converts the arguments into the table layout of `util:get_args`: positionals are
+    /// available both by their 0-based index and by name, flags by name.
+    fn to_lua(self, lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
+        let table = lua.create_table()?;
+        table.set("length", self.positional.len())?;
+        for (i, (name, value)) in self.positional.into_iter().enumerate() {
+            table.set(i, value.clone())?;
+            table.set(name, value)?;
+        }
+        for (name, value) in self.flags {
+            table.set(name, value)?;
+        }
+        Ok(mlua::Value::Table(table))
+    }
+}
+
+/// The keys of the `util:get_args` table that the arguments can't be named after.
+const RESERVED_NAMES: &[&str] = &["channel", "user", "length", "ctx"];
+
+/// Rejects the arguments and flags that would overwrite the other keys of `util:get_args`.
+pub fn check_names(args: &[ArgSpec], flags: &[FlagSpec]) -> Result<(), String> {
+    let names = args
+        .iter()
+        .map(|arg| &arg.name)
+        .chain(flags.iter().map(|flag| &flag.name));
+    for name in names {
+        if RESERVED_NAMES.contains(&name.as_str()) {
+            return Err(format!("`{}` is reserved and can't name an argument", name));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArgError(pub String);
+
+impl std::fmt::Display for ArgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Splits the message into tokens, keeping `"quoted strings"` and `'quoted strings'` together.
+pub fn tokenize(input: &str) -> Result<Vec<String>, ArgError> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut quote: Option<char> = None;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some('"') if c == '\\' => match chars.next() {
+                Some(escaped) => current.push(escaped),
+                None => current.push(c),
+            },
+            Some(_) => current.push(c),
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                in_token = true;
+            }
+            None if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(std::mem::replace(&mut current, String::new()));
+                    in_token = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_token = true;
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err(ArgError("unterminated quote".to_owned()));
+    }
+    if in_token {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+impl ArgSchema {
+    /// Generates the usage line, e.g. `song queue <playlist:url> [count:int] [--shuffle|-s]`.
+    pub fn usage(&self, command: &str) -> String {
+        let mut parts = vec![command.to_owned()];
+        for arg in &self.args {
+            let name = if arg.rest {
+                format!("{}:{}...", arg.name, arg.kind)
+            } else {
+                format!("{}:{}", arg.name, arg.kind)
+            };
+            if arg.optional || arg.default.is_some() {
+                parts.push(format!("[{}]", name));
+            } else {
+                parts.push(format!("<{}>", name));
+            }
+        }
+        for flag in &self.flags {
+            let mut name = format!("--{}", flag.name);
+            if let Some(short) = flag.short {
+                name += &format!("|-{}", short);
+            }
+            if let Some(kind) = flag.kind {
+                name += &format!(" <{}>", kind);
+            }
+            parts.push(format!("[{}]", name));
+        }
+        parts.join(" ")
+    }
+
+    fn find_flag(&self, token: &str) -> Option<&FlagSpec> {
+        if token.starts_with("--") {
+            let name = &token[2..];
+            self.flags.iter().find(|f| f.name == name)
+        } else {
+            let mut chars = token[1..].chars();
+            match (chars.next(), chars.next()) {
+                (Some(short), None) => self.flags.iter().find(|f| f.short == Some(short)),
+                _ => None,
+            }
+        }
+    }
+
+    /// Validates the tokens against the schema and coerces them to their declared types.
+    pub fn parse(&self, tokens: Vec<String>) -> Result<ParsedArgs, ArgError> {
+        let mut parsed = ParsedArgs::default();
+        let mut positional = Vec::new();
+        let mut tokens = tokens.into_iter();
+        let mut flags_done = false;
+
+        while let Some(token) = tokens.next() {
+            let is_flag = !flags_done
+                && token.starts_with('-')
+                && token.len() > 1
+                && token.parse::<f64>().is_err();
+            if !is_flag {
+                positional.push(token);
+                continue;
+            }
+            if token == "--" {
+                flags_done = true;
+                continue;
+            }
+
+            let (token, inline_value) = match token.find('=') {
+                Some(i) if token.starts_with("--") => {
+                    (token[..i].to_owned(), Some(token[i + 1..].to_owned()))
+                }
+                _ => (token, None),
+            };
+            let flag = self
+                .find_flag(&token)
+                .ok_or_else(|| ArgError(format!("unknown flag `{}`", token)))?;
+            let value = match flag.kind {
+                Some(kind) => {
+                    let raw = inline_value
+                        .or_else(|| tokens.next())
+                        .ok_or_else(|| ArgError(format!("flag `--{}` needs a value", flag.name)))?;
+                    coerce(&flag.name, kind, &raw)?
+                }
+                None if inline_value.is_some() => {
+                    return Err(ArgError(format!("flag `--{}` takes no value", flag.name)))
+                }
+                None => ArgValue::Bool(true),
+            };
+            parsed.flags.push((flag.name.clone(), value));
+        }
+
+        for flag in &self.flags {
+            if parsed.flags.iter().any(|(name, _)| name == &flag.name) {
+                continue;
+            }
+            let value = match (flag.kind, &flag.default) {
+                (Some(kind), Some(default)) => coerce(&flag.name, kind, default)?,
+                (Some(_), None) => continue,
+                (None, _) => ArgValue::Bool(false),
+            };
+            parsed.flags.push((flag.name.clone(), value));
+        }
+
+        let mut positional = positional.into_iter();
+        for arg in &self.args {
+            let raw = if arg.rest {
+                let rest = positional.by_ref().collect::<Vec<_>>();
+                if rest.is_empty() {
+                    None
+                } else {
+                    Some(rest.join(" "))
+                }
+            } else {
+                positional.next()
+            };
+            let raw = match raw.or_else(|| arg.default.clone()) {
+                Some(raw) => raw,
+                None if arg.optional => continue,
+                None => return Err(ArgError(format!("missing argument `{}`", arg.name))),
+            };
+            parsed
+                .positional
+                .push((arg.name.clone(), coerce(&arg.name, arg.kind, &raw)?));
+        }
+        if let Some(extra) = positional.next() {
+            return Err(ArgError(format!("unexpected argument `{}`", extra)));
+        }
+
+        Ok(parsed)
+    }
+}
+
+fn coerce(name: &str, kind: ArgKind, raw: &str) -> Result<ArgValue, ArgError> {
+    let invalid = || ArgError(format!("`{}` is not a valid {} for `{}`", raw, kind, name));
+    Ok(match kind {
+        ArgKind::String => ArgValue::Str(raw.to_owned()),
+        ArgKind::Int => ArgValue::Int(raw.parse().map_err(|_| invalid())?),
+        ArgKind::Url => match reqwest::Url::parse(raw) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+                ArgValue::Str(url.into_string())
+            }
+            _ => return Err(invalid()),
+        },
+        ArgKind::Username => {
+            let name = raw.trim_start_matches('@').to_lowercase();
+            let is_valid = !name.is_empty()
+                && name.len() <= 25
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !is_valid {
+                return Err(invalid());
+            }
+            ArgValue::Str(name)
+        }
+        ArgKind::Duration => ArgValue::Int(parse_duration(raw).ok_or_else(invalid)?),
+    })
+}
+
+/// Parses durations like `90`, `90s`, `5m`, `1h30m` or `2d` into seconds.
+pub fn parse_duration(raw: &str) -> Option<i64> {
+    if let Ok(secs) = raw.parse::<i64>() {
+        return if secs >= 0 { Some(secs) } else { None };
+    }
+
+    let mut total: i64 = 0;
+    let mut number = String::new();
+    for c in raw.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        let value = number.parse::<i64>().ok()?;
+        total = total.checked_add(value.checked_mul(unit)?)?;
+        number.clear();
+    }
+
+    if number.is_empty() && !raw.is_empty() {
+        Some(total)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arg(name: &str, kind: ArgKind) -> ArgSpec {
+        ArgSpec {
+            name: name.to_owned(),
+            kind,
+            optional: false,
+            default: None,
+            rest: false,
+        }
+    }
+
+    fn flag(name: &str, short: Option<char>, kind: Option<ArgKind>) -> FlagSpec {
+        FlagSpec {
+            name: name.to_owned(),
+            short,
+            kind,
+            default: None,
+        }
+    }
+
+    fn tokens(input: &[&str]) -> Vec<String> {
+        input.iter().map(|token| token.to_string()).collect()
+    }
+
+    fn queue_schema() -> ArgSchema {
+        ArgSchema {
+            args: vec![
+                arg("playlist", ArgKind::Url),
+                ArgSpec {
+                    default: Some("10".to_owned()),
+                    ..arg("count", ArgKind::Int)
+                },
+            ],
+            flags: vec![
+                flag("shuffle", Some('s'), None),
+                flag("skip", None, Some(ArgKind::Duration)),
+            ],
+        }
+    }
+
+    #[test]
+    fn tokenize_keeps_quoted_strings_together() {
+        assert_eq!(
+            tokenize(r#"one "two  three" 'four five'   six"#),
+            Ok(tokens(&["one", "two  three", "four five", "six"]))
+        );
+        assert_eq!(tokenize(r#""say \"hi\"""#), Ok(tokens(&[r#"say "hi""#])));
+        assert_eq!(
+            tokenize(r#"'no \escapes'"#),
+            Ok(tokens(&[r#"no \escapes"#]))
+        );
+        assert_eq!(
+            tokenize(r#"empty "" quotes"#),
+            Ok(tokens(&["empty", "", "quotes"]))
+        );
+        assert_eq!(tokenize("  "), Ok(vec![]));
+    }
+
+    #[test]
+    fn tokenize_rejects_unterminated_quotes() {
+        assert!(tokenize(r#"one "two"#).is_err());
+        assert!(tokenize("'").is_err());
+    }
+
+    #[test]
+    fn parse_applies_defaults_and_flags() {
+        let parsed = queue_schema()
+            .parse(tokens(&["https://example.com/list", "-s", "--skip=1m30s"]))
+            .unwrap();
+        assert_eq!(
+            parsed.positional,
+            vec![
+                (
+                    "playlist".to_owned(),
+                    ArgValue::Str("https://example.com/list".to_owned())
+                ),
+                ("count".to_owned(), ArgValue::Int(10)),
+            ]
+        );
+        assert_eq!(
+            parsed.flags,
+            vec![
+                ("shuffle".to_owned(), ArgValue::Bool(true)),
+                ("skip".to_owned(), ArgValue::Int(90)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_fills_unset_flags() {
+        let parsed = queue_schema()
+            .parse(tokens(&["https://example.com/list", "5"]))
+            .unwrap();
+        assert_eq!(parsed.positional[1], ("count".to_owned(), ArgValue::Int(5)));
+        // Typed flags without a default are left out
+        assert_eq!(
+            parsed.flags,
+            vec![("shuffle".to_owned(), ArgValue::Bool(false))]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_invalid_invocations() {
+        let schema = queue_schema();
+        let error = |input: &[&str]| schema.parse(tokens(input)).unwrap_err().0;
+        assert_eq!(error(&[]), "missing argument `playlist`");
+        assert_eq!(
+            error(&["ftp://example.com"]),
+            "`ftp://example.com` is not a valid url for `playlist`"
+        );
+        assert_eq!(
+            error(&["https://example.com", "ten"]),
+            "`ten` is not a valid int for `count`"
+        );
+        assert_eq!(
+            error(&["https://example.com", "1", "2"]),
+            "unexpected argument `2`"
+        );
+        assert_eq!(
+            error(&["https://example.com", "--nope"]),
+            "unknown flag `--nope`"
+        );
+        assert_eq!(
+            error(&["https://example.com", "--skip"]),
+            "flag `--skip` needs a value"
+        );
+        assert_eq!(
+            error(&["https://example.com", "--shuffle=yes"]),
+            "flag `--shuffle` takes no value"
+        );
+    }
+
+    #[test]
+    fn parse_treats_negative_numbers_and_escaped_dashes_as_positionals() {
+        let schema = ArgSchema {
+            args: vec![arg("offset", ArgKind::Int), arg("name", ArgKind::String)],
+            flags: vec![flag("verbose", Some('v'), None)],
+        };
+        let parsed = schema.parse(tokens(&["-5", "--", "-v"])).unwrap();
+        assert_eq!(
+            parsed.positional,
+            vec![
+                ("offset".to_owned(), ArgValue::Int(-5)),
+                ("name".to_owned(), ArgValue::Str("-v".to_owned())),
+            ]
+        );
+        assert_eq!(
+            parsed.flags,
+            vec![("verbose".to_owned(), ArgValue::Bool(false))]
+        );
+    }
+
+    #[test]
+    fn parse_collects_the_rest() {
+        let schema = ArgSchema {
+            args: vec![
+                arg("user", ArgKind::Username),
+                ArgSpec {
+                    rest: true,
+                    ..arg("reason", ArgKind::String)
+                },
+            ],
+            flags: vec![],
+        };
+        let parsed = schema
+            .parse(tokens(&["@Forsen", "for", "being", "late"]))
+            .unwrap();
+        assert_eq!(
+            parsed.positional,
+            vec![
+                ("user".to_owned(), ArgValue::Str("forsen".to_owned())),
+                (
+                    "reason".to_owned(),
+                    ArgValue::Str("for being late".to_owned())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_duration_accepts_units() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("90s"), Some(90));
+        assert_eq!(parse_duration("5m"), Some(300));
+        assert_eq!(parse_duration("1h30m"), Some(5400));
+        assert_eq!(parse_duration("2D"), Some(172_800));
+        assert_eq!(parse_duration("1m1m"), Some(120));
+    }
+
+    #[test]
+    fn parse_duration_rejects_malformed_durations() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("-5"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("1h30"), None);
+        assert_eq!(parse_duration("5w"), None);
+        assert_eq!(parse_duration("99999999999999999d"), None);
+    }
+
+    #[test]
+    fn check_names_rejects_reserved_names() {
+        assert!(check_names(&[arg("channel", ArgKind::String)], &[]).is_err());
+        assert!(check_names(&[], &[flag("user", None, None)]).is_err());
+        assert!(check_names(&[arg("ctx", ArgKind::String)], &[]).is_err());
+        assert!(check_names(&queue_schema().args, &queue_schema().flags).is_ok());
+    }
+}
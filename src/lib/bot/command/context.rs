@@ -0,0 +1,104 @@
+//! The `ctx` table describing the invocation. The commands declared with `"context": true`
+//! receive it alone, the others after the `channel, user, ...args` varargs, where
+//! `util:get_args(...)` picks it up as `args.ctx`:
+//!
+//! ```lua
+//! local ctx = ...
+//! if ctx.is_mod then
+//!     return ctx.display_name .. " ran " .. ctx.command .. " with " .. #ctx.args .. " arguments"
+//! end
+//! ```
+use mlua::ToLua;
+
+use super::ScriptArgs;
+use crate::bot::emotes;
+use crate::bot::permissions::Roles;
+
+/// The `__name` of the metatable of the `ctx` table.
+pub const CTX_NAME: &str = "ctx";
+/// The key of the [`Caller`] in the metatable of the `ctx` table.
+const CALLER_KEY: &str = "__caller";
+
+/// Who invoked the command. It's kept in the metatable of `ctx`, out of the scripts' reach,
+/// so that a table of their own with forged roles isn't taken for the `ctx` table.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub channel: String,
+    pub user: String,
+    pub roles: Roles,
+}
+
+impl mlua::UserData for Caller {}
+
+/// The caller of the `ctx` table, `None` for any other table.
+pub fn caller(table: &mlua::Table) -> Option<Caller> {
+    let caller = table
+        .get_metatable()?
+        .raw_get::<_, mlua::AnyUserData>(CALLER_KEY)
+        .ok()?;
+    let caller = caller.borrow::<Caller>().ok()?;
+    Some(caller.clone())
+}
+
+/// Builds the `ctx` table from the invocation and the tags of its message.
+pub(super) fn build<'lua>(args: ScriptArgs, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
+    let ScriptArgs {
+        channel,
+        user,
+        args,
+        parsed,
+        message_id,
+        tags,
+        message,
+        command,
+        ..
+    } = args;
+    let tag = |name: &str| tags.get(name).filter(|value| !value.is_empty()).cloned();
+    let flag = |name: &str| tags.get(name).map(|value| value == "1").unwrap_or(false);
+
+    let badges = lua.create_table()?;
+    for badge in tag("badges").unwrap_or_default().split(',') {
+        let mut parts = badge.splitn(2, '/');
+        if let (Some(name), Some(version)) = (parts.next(), parts.next()) {
+            badges.set(name, version)?;
+        }
+    }
+    let has_badge = |name: &str| badges.contains_key(name).unwrap_or(false);
+    let is_broadcaster = has_badge("broadcaster");
+    let caller = Caller {
+        channel: channel.clone(),
+        user: user.clone(),
+        roles: Roles {
+            broadcaster: is_broadcaster,
+            moderator: flag("mod") || is_broadcaster,
+            vip: has_badge("vip"),
+            subscriber: flag("subscriber") || has_badge("founder"),
+        },
+    };
+
+    let ctx = lua.create_table()?;
+    ctx.set("channel", channel)?;
+    ctx.set("user", user)?;
+    ctx.set("user_id", tag("user-id"))?;
+    ctx.set("display_name", tag("display-name"))?;
+    ctx.set("color", tag("color"))?;
+    ctx.set("is_broadcaster", caller.roles.broadcaster)?;
+    ctx.set("is_mod", caller.roles.moderator)?;
+    ctx.set("is_vip", caller.roles.vip)?;
+    ctx.set("is_sub", caller.roles.subscriber)?;
+    ctx.set("badges", badges)?;
+    let emotes = emotes::parse(&tag("emotes").unwrap_or_default(), &message);
+    ctx.set("emotes", lua.create_sequence_from(emotes)?)?;
+    ctx.set("bits", tag("bits").and_then(|bits| bits.parse::<u64>().ok()))?;
+    ctx.set("message", message)?;
+    ctx.set("message_id", message_id)?;
+    ctx.set("command", command)?;
+    ctx.set("args", lua.create_sequence_from(args)?)?;
+    ctx.set("parsed", parsed.map(|parsed| parsed.to_lua(lua)).transpose()?)?;
+    ctx.set("tags", tags)?;
+    let meta = lua.create_table()?;
+    meta.set("__name", CTX_NAME)?;
+    meta.set(CALLER_KEY, caller)?;
+    ctx.set_metatable(Some(meta));
+    Ok(mlua::Value::Table(ctx))
+}
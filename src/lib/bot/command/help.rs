@@ -0,0 +1,73 @@
+//! Renders `help` output from the command metadata in `commands.json`.
+use super::CommandData;
+use crate::bot::permissions::Permission;
+
+/// Renders a one-line synopsis of the command, e.g.
+/// `song queue <playlist:url> [count:int] — Queues songs | requires: moderator | cooldown: 30s per user`.
+pub fn synopsis(data: &CommandData<'_>) -> String {
+    let head = match &data.schema {
+        Some(schema) => schema.usage(&data.id),
+        None => data.id.clone(),
+    };
+    let mut parts = vec![match data.description.as_ref().or_else(|| data.usage.as_ref()) {
+        Some(text) => format!("{} — {}", head, text),
+        None => head,
+    }];
+
+    if !data.aliases.is_empty() {
+        parts.push(format!("aliases: {}", data.aliases.join(", ")));
+    }
+    if data.permission != Permission::Everyone {
+        parts.push(format!("requires: {}", data.permission));
+    }
+    let cooldowns = vec![
+        (data.cooldown.global, "globally"),
+        (data.cooldown.channel, "per channel"),
+        (data.cooldown.user, "per user"),
+    ]
+    .into_iter()
+    .filter_map(|(secs, scope)| match secs {
+        Some(secs) if secs > 0 => Some(format!("{}s {}", secs, scope)),
+        _ => None,
+    })
+    .collect::<Vec<_>>();
+    if !cooldowns.is_empty() {
+        parts.push(format!("cooldown: {}", cooldowns.join(", ")));
+    }
+    if let Some(example) = data.examples.first() {
+        parts.push(format!("e.g. {}", example));
+    }
+
+    parts.join(" | ")
+}
+
+/// Returns up to three candidates closest to the given name, closest first.
+pub fn suggest<'a, I: IntoIterator<Item = &'a str>>(name: &str, candidates: I) -> Vec<&'a str> {
+    let max_distance = std::cmp::max(2, name.chars().count() / 3);
+    let mut scored = candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect::<Vec<_>>();
+    scored.sort();
+    scored.dedup_by(|a, b| a.1 == b.1);
+    scored.into_iter().take(3).map(|(_, c)| c).collect()
+}
+
+/// The Levenshtein distance between two strings.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous + if ca == *cb { 0 } else { 1 };
+            previous = row[j + 1];
+            row[j + 1] = std::cmp::min(substitution, std::cmp::min(row[j], row[j + 1]) + 1);
+        }
+    }
+
+    row[b.len()]
+}
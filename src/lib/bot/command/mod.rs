@@ -1,40 +1,240 @@
+pub mod args;
+pub mod context;
+pub mod help;
+pub mod report;
+
+use super::cooldown::Cooldown;
+use super::permissions::Permission;
 use super::util;
+use crate::lua::sandbox::{self, Sandbox};
 use crate::BackendError;
+use args::{ArgSchema, ArgSpec, FlagSpec, ParsedArgs};
+use mlua::{ToLua, ToLuaMulti};
 use serde::Deserialize;
-use std::collections::HashMap;
+use report::{LoadError, LoadErrorKind, LoadReport};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
 
 pub(crate) fn load_lua<'a>(
     lua: &'a mlua::Lua,
     name: &str,
     source: &str,
+    sandbox: &Sandbox,
 ) -> Result<mlua::Function<'a>, BackendError> {
-    lua.load(source).into_function().map_err(|e| {
-        BackendError::from(format!(
-            "Failed to load the LUA script for `{}`: {}",
-            name, e
-        ))
+    sandbox::command_env(lua, name, sandbox)
+        .and_then(|env| lua.load(source).set_environment(env))
+        .and_then(|chunk| chunk.into_function())
+        .map_err(|e| {
+            BackendError::from(format!(
+                "Failed to load the LUA script for `{}`: {}",
+                name, e
+            ))
+        })
+}
+
+/// The settings a subcommand inherits from its parent.
+#[derive(Clone, Default)]
+struct Inherited {
+    path: Option<String>,
+    cooldown: Cooldown,
+    permission: Permission,
+    sandbox: Sandbox,
+    timeout: Option<u64>,
+}
+
+impl Inherited {
+    fn child(&self, name: &str, command: &CommandJSON) -> Inherited {
+        Inherited {
+            path: Some(self.qualify(name)),
+            cooldown: command.cooldown.unwrap_or_default().inherit(self.cooldown),
+            permission: command.permission.unwrap_or(self.permission),
+            sandbox: command.sandbox.clone().unwrap_or_else(|| self.sandbox.clone()),
+            timeout: command.timeout.or(self.timeout),
+        }
+    }
+
+    fn qualify(&self, name: &str) -> String {
+        match &self.path {
+            Some(path) => format!("{} {}", path, name),
+            None => name.to_owned(),
+        }
+    }
+}
+
+/// Reads, transpiles and compiles the script of a command.
+pub(crate) fn compile<'a>(
+    lua: &'a mlua::Lua,
+    id: &str,
+    path: &str,
+    sandbox: &Sandbox,
+) -> Result<mlua::Function<'a>, LoadError> {
+    let source = util::read_script(path).map_err(|e| e.command(id))?;
+    load_lua(lua, id, &source, sandbox).map_err(|e| {
+        LoadError::new(LoadErrorKind::Syntax, e.inner.to_string())
+            .command(id)
+            .file(path)
     })
 }
 
+/// The commands handled by the bot itself, which the top-level commands can't shadow.
+pub const BUILTINS: &[&str] = &[
+    "help", "commands", "profile", "timers", "stop", "reload", "perm", "running", "cancel", "join",
+    "part", "channels", "workers",
+];
+
+/// Drops the top-level commands named after a built-in, and the aliases that shadow a built-in,
+/// a command or another alias on the same level. The aliases of the previous versions `kept`
+/// after a schema error are checked first, since they were valid until now.
+fn check_aliases(
+    commands: &mut HashMap<String, CommandJSON>,
+    kept: &mut HashMap<String, Command<'_>>,
+    parent: &Inherited,
+    report: &mut LoadReport,
+) {
+    let is_top_level = parent.path.is_none();
+    if is_top_level {
+        let mut shadowing = commands
+            .keys()
+            .filter(|name| BUILTINS.contains(&name.as_str()))
+            .cloned()
+            .collect::<Vec<_>>();
+        shadowing.sort();
+        for name in shadowing {
+            commands.remove(&name);
+            report.errors.push(
+                LoadError::new(
+                    LoadErrorKind::Schema,
+                    format!("`{}` is a built-in command", name),
+                )
+                .command(&name),
+            );
+        }
+    }
+
+    let mut taken = commands
+        .keys()
+        .chain(kept.keys())
+        .cloned()
+        .collect::<HashSet<_>>();
+    if is_top_level {
+        taken.extend(BUILTINS.iter().map(|name| name.to_string()));
+    }
+    let mut is_free = |name: &str, alias: &String| {
+        if taken.insert(alias.clone()) {
+            return true;
+        }
+        report.errors.push(
+            LoadError::new(
+                LoadErrorKind::Schema,
+                format!(
+                    "The alias `{}` collides with a built-in, another command or alias",
+                    parent.qualify(alias)
+                ),
+            )
+            .command(parent.qualify(name)),
+        );
+        false
+    };
+
+    let mut names = kept.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        let node = kept.get_mut(&name).unwrap();
+        node.aliases.retain(|alias| is_free(name.as_str(), alias));
+        let aliases = &node.aliases;
+        if let Some(data) = node.data.as_mut() {
+            data.aliases.retain(|alias| aliases.contains(alias));
+        }
+    }
+
+    let mut names = commands.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        let aliases = match commands.get_mut(&name).and_then(|c| c.aliases.as_mut()) {
+            Some(aliases) => aliases,
+            None => continue,
+        };
+        aliases.retain(|alias| is_free(name.as_str(), alias));
+    }
+}
+
 fn transform<'a>(
     lua: &'a mlua::Lua,
-    commands: HashMap<String, CommandJSON>,
-) -> HashMap<String, Command> {
-    let mut transformed: HashMap<String, Command> = HashMap::new();
-    for (name, command) in commands {
-        let data: Option<CommandData> = match (command.usage, command.script) {
-            (Some(usage), Some(script)) => Some(CommandData {
-                usage,
+    commands: HashMap<String, serde_json::Value>,
+    parent: &Inherited,
+    previous: Option<&HashMap<String, Command<'a>>>,
+    report: &mut LoadReport,
+) -> HashMap<String, Command<'a>> {
+    let mut parsed: HashMap<String, CommandJSON> = HashMap::new();
+    let mut kept: HashMap<String, Command> = HashMap::new();
+
+    for (name, value) in commands {
+        let command = serde_json::from_value::<CommandJSON>(value)
+            .map_err(|e| e.to_string())
+            .and_then(|command| {
+                let args = command.args.as_deref().unwrap_or_default();
+                let flags = command.flags.as_deref().unwrap_or_default();
+                args::check_names(args, flags).map(|_| command)
+            });
+        match command {
+            Ok(command) => {
+                parsed.insert(name, command);
+            }
+            Err(e) => {
+                let id = parent.qualify(&name);
+                report
+                    .errors
+                    .push(LoadError::new(LoadErrorKind::Schema, e).command(&id));
+                if let Some(node) = previous.and_then(|p| p.get(&name)) {
+                    report.kept.push(id);
+                    kept.insert(name, node.clone());
+                }
+            }
+        }
+    }
+    check_aliases(&mut parsed, &mut kept, parent, report);
+    let mut transformed = kept;
+
+    for (name, command) in parsed {
+        let id = parent.qualify(&name);
+        let inherited = parent.child(&name, &command);
+        let previous = previous.and_then(|p| p.get(&name));
+
+        let script = command.script.as_ref().and_then(|path| {
+            compile(lua, &id, path, &inherited.sandbox)
+                .map_err(|e| report.errors.push(e))
+                .ok()
+                .or_else(|| {
+                    let script = previous?.data.as_ref()?.script.clone();
+                    report.kept.push(id.clone());
+                    Some(script)
+                })
+        });
+        let data: Option<CommandData> = match (command.script, script) {
+            (Some(path), Some(script)) => Some(CommandData {
+                usage: command.usage,
+                description: command.description,
+                examples: command.examples.unwrap_or_default(),
+                aliases: command.aliases.clone().unwrap_or_default(),
                 name: name.clone(),
+                id,
+                cooldown: inherited.cooldown,
+                permission: inherited.permission,
+                sandbox: inherited.sandbox.clone(),
+                timeout: inherited.timeout,
+                reply_via_whisper: command.reply_via_whisper.unwrap_or(false),
+                reply_in_thread: command.reply_in_thread.unwrap_or(false),
+                context: command.context.unwrap_or(false),
+                schema: match (command.args, command.flags) {
+                    (None, None) => None,
+                    (args, flags) => Some(ArgSchema {
+                        args: args.unwrap_or_default(),
+                        flags: flags.unwrap_or_default(),
+                    }),
+                },
                 is_expensive: command.is_expensive.unwrap_or(false),
-                path: script.clone(),
-                script: load_lua(
-                    &lua,
-                    &name,
-                    &util::load_file(&script)
-                        .unwrap_or_else(|e| panic!("Failed to load file {}: {}", script, e)),
-                )
-                .unwrap_or_else(|e| panic!("Failed to load the script {}: {}", script, e)),
+                path,
+                script,
             }),
             _ => None,
         };
@@ -43,50 +243,165 @@ fn transform<'a>(
             name,
             Command {
                 data,
-                commands: if command.commands.is_some() {
-                    Some(transform(&lua, command.commands.unwrap()))
-                } else {
-                    None
-                },
+                aliases: command.aliases.unwrap_or_default(),
+                commands: command.commands.map(|commands| {
+                    transform(
+                        lua,
+                        commands,
+                        &inherited,
+                        previous.and_then(|p| p.commands.as_ref()),
+                        report,
+                    )
+                }),
             },
         );
     }
     transformed
 }
 
+/// Loads the commands file, collecting every error into the report instead of failing.
+/// When reloading, the broken commands keep their `previous` working versions, and a broken
+/// commands file keeps all of them.
 pub fn load_commands<'a>(
     lua: &'a mlua::Lua,
     path: &str,
-) -> Result<HashMap<String, Command<'a>>, BackendError> {
-    Ok(transform(
-        lua,
-        util::parse_json(&util::load_file(path).map_err(|e| {
-            BackendError::from(format!(
-                "Failed to locate the commands json at {}: {}",
-                path, e
-            ))
-        })?)?,
-    ))
+    previous: Option<&HashMap<String, Command<'a>>>,
+) -> (HashMap<String, Command<'a>>, LoadReport) {
+    let mut report = LoadReport::default();
+    let json = util::read_script(path).and_then(|json| {
+        serde_json::from_str::<HashMap<String, serde_json::Value>>(&json)
+            .map_err(|e| LoadError::new(LoadErrorKind::Schema, e.to_string()).file(path))
+    });
+    let commands = match json {
+        Ok(json) => transform(lua, json, &Inherited::default(), previous, &mut report),
+        Err(e) => {
+            report.errors.push(e);
+            previous.cloned().unwrap_or_default()
+        }
+    };
+    (commands, report)
 }
 
 #[derive(Clone, Deserialize)]
 struct CommandJSON {
+    /// Free-text usage, shown by `help` when there's no description.
     pub usage: Option<String>,
+    pub description: Option<String>,
+    pub examples: Option<Vec<String>>,
+    pub aliases: Option<Vec<String>>,
     pub is_expensive: Option<bool>,
     pub script: Option<String>,
-    pub commands: Option<HashMap<String, CommandJSON>>,
+    pub cooldown: Option<Cooldown>,
+    pub permission: Option<Permission>,
+    pub sandbox: Option<Sandbox>,
+    pub timeout: Option<u64>,
+    pub reply_via_whisper: Option<bool>,
+    pub reply_in_thread: Option<bool>,
+    pub context: Option<bool>,
+    pub args: Option<Vec<ArgSpec>>,
+    pub flags: Option<Vec<FlagSpec>>,
+    /// The subcommands, parsed one by one so that a malformed one doesn't break its siblings.
+    pub commands: Option<HashMap<String, serde_json::Value>>,
 }
 
 #[derive(Clone)]
 pub struct CommandData<'a> {
-    pub usage: String,
+    pub usage: Option<String>,
+    pub description: Option<String>,
+    pub examples: Vec<String>,
+    pub aliases: Vec<String>,
     pub is_expensive: bool,
     pub path: String,
     pub name: String,
+    /// The full path of the command, e.g. `song queue`.
+    pub id: String,
+    pub cooldown: Cooldown,
+    pub permission: Permission,
+    pub sandbox: Sandbox,
+    /// How long the script may run in seconds, overriding the global timeout. `0` disables it.
+    pub timeout: Option<u64>,
+    /// Whether the reply is whispered to the caller instead of sent to the channel.
+    pub reply_via_whisper: bool,
+    /// Whether the reply is threaded under the message that invoked the command.
+    pub reply_in_thread: bool,
+    /// Whether the script receives a single `ctx` table instead of the varargs, see `context`.
+    pub context: bool,
+    /// The declared arguments, if the command wants them validated before it runs.
+    pub schema: Option<ArgSchema>,
     pub script: mlua::Function<'a>,
 }
 
+#[derive(Clone)]
 pub struct Command<'a> {
     pub data: Option<CommandData<'a>>,
+    /// Alternative names of the command, resolved by `util::find_command`.
+    pub aliases: Vec<String>,
     pub commands: Option<HashMap<String, Command<'a>>>,
 }
+
+/// A summary of a scripted command, shared with the Lua side for listings.
+#[derive(Debug, Clone)]
+pub struct CommandEntry {
+    pub id: String,
+    /// The invocation syntax, e.g. `song queue <playlist> [count]`.
+    pub synopsis: String,
+    pub usage: Option<String>,
+    pub description: Option<String>,
+    pub permission: Permission,
+}
+
+impl<'a> From<&CommandData<'a>> for CommandEntry {
+    fn from(data: &CommandData<'a>) -> Self {
+        CommandEntry {
+            id: data.id.clone(),
+            synopsis: help::synopsis(data),
+            usage: data.usage.clone(),
+            description: data.description.clone(),
+            permission: data.permission,
+        }
+    }
+}
+
+pub type CommandList = Arc<RwLock<Vec<CommandEntry>>>;
+
+/// The arguments of a script invocation. They are owned, so they can be sent to another thread
+/// and converted in its Lua state. Scripts receive `channel, user, ...args, ctx`, where the
+/// arguments are either the raw words of the message or, for commands with declared arguments,
+/// a single table of the parsed arguments. `util:get_args(...)` handles all of it, with the
+/// `ctx` table under `args.ctx`. The commands declared with `"context": true` receive
+/// the `ctx` table alone.
+#[derive(Debug, Clone)]
+pub struct ScriptArgs {
+    pub channel: String,
+    pub user: String,
+    pub args: Vec<String>,
+    pub parsed: Option<ParsedArgs>,
+    /// The `id` tag of the invoking message, for `bot:reply`.
+    pub message_id: Option<String>,
+    pub tags: HashMap<String, String>,
+    /// The text of the invoking message.
+    pub message: String,
+    /// The full path of the command, e.g. `song queue`.
+    pub command: String,
+    pub context: bool,
+}
+
+impl<'lua> ToLuaMulti<'lua> for ScriptArgs {
+    fn to_lua_multi(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::MultiValue<'lua>> {
+        if self.context {
+            return Ok(mlua::MultiValue::from_vec(vec![context::build(self, lua)?]));
+        }
+        let ctx = context::build(self.clone(), lua)?;
+        let mut values = vec![self.channel.to_lua(lua)?, self.user.to_lua(lua)?];
+        match self.parsed {
+            Some(parsed) => values.push(parsed.to_lua(lua)?),
+            None => {
+                for arg in self.args {
+                    values.push(arg.to_lua(lua)?);
+                }
+            }
+        }
+        values.push(ctx);
+        Ok(mlua::MultiValue::from_vec(values))
+    }
+}
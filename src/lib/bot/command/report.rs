@@ -0,0 +1,129 @@
+//! Collects the errors encountered while loading `commands.json`, so that a single broken
+//! script doesn't take the rest of the commands down with it.
+use crate::bot::util;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadErrorKind {
+    /// The script or the commands file couldn't be read.
+    MissingFile,
+    /// The PPGA transpiler rejected the script.
+    Transpile,
+    /// Lua rejected the (transpiled) script.
+    Syntax,
+    /// The command's JSON doesn't match the expected schema.
+    Schema,
+}
+
+impl std::fmt::Display for LoadErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            LoadErrorKind::MissingFile => "missing file",
+            LoadErrorKind::Transpile => "PPGA error",
+            LoadErrorKind::Syntax => "Lua error",
+            LoadErrorKind::Schema => "invalid JSON",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadError {
+    pub kind: LoadErrorKind,
+    /// The full path of the command, or `None` if the whole commands file is broken.
+    pub command: Option<String>,
+    /// The script or the commands file at fault.
+    pub file: Option<String>,
+    pub message: String,
+}
+
+impl LoadError {
+    pub fn new<S: Into<String>>(kind: LoadErrorKind, message: S) -> Self {
+        LoadError {
+            kind,
+            command: None,
+            file: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn command<S: Into<String>>(self, command: S) -> Self {
+        LoadError {
+            command: Some(command.into()),
+            ..self
+        }
+    }
+
+    pub fn file<S: Into<String>>(self, file: S) -> Self {
+        LoadError {
+            file: Some(file.into()),
+            ..self
+        }
+    }
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(command) = &self.command {
+            write!(f, "`{}` ", command)?;
+        }
+        if let Some(file) = &self.file {
+            write!(f, "[{}] ", file)?;
+        }
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// The outcome of loading the commands file.
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    pub errors: Vec<LoadError>,
+    /// The commands that failed to load but kept their previous working version.
+    pub kept: Vec<String>,
+}
+
+impl LoadReport {
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn log(&self) {
+        for error in &self.errors {
+            log::error!("Failed to load a command: {}", error);
+        }
+        if !self.kept.is_empty() {
+            log::warn!(
+                "Kept the previous versions of: {}",
+                self.kept.join(", ")
+            );
+        }
+    }
+
+    /// Summarizes the report in as few chat messages as possible.
+    pub fn summary(&self) -> Vec<String> {
+        if self.is_ok() {
+            return vec!["👉 Successfully reloaded the commands file.".to_owned()];
+        }
+        let items = self
+            .errors
+            .iter()
+            .map(|error| match &error.command {
+                Some(command) if self.kept.contains(command) => {
+                    format!("{} ({}, kept the previous version)", command, error.kind)
+                }
+                Some(command) => format!("{} ({})", command, error.kind),
+                None => match &error.file {
+                    Some(file) => format!("{} ({})", file, error.kind),
+                    None => format!("the commands file ({})", error.kind),
+                },
+            })
+            .collect::<Vec<_>>();
+        util::pack_messages(
+            "WAYTOODANK ❗❗ failed to load: ",
+            &items,
+            ", ",
+            util::MAX_MESSAGE_LENGTH,
+        )
+    }
+}
@@ -1,10 +1,279 @@
-use serde::Deserialize;
-use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 
 #[derive(Debug, Deserialize)]
 pub struct BotConfig {
     pub channels: HashSet<String>,
     pub gym_staff: HashSet<String>,
+    /// The default command prefixes, used by every channel without its own.
+    #[serde(default = "default_prefixes")]
+    pub prefixes: Vec<String>,
+    /// Whether mentioning the bot (`@bot ping`, `bot, ping`) triggers commands by default.
+    #[serde(default)]
+    pub mention: bool,
+    /// The message sent to every channel once the bot is ready, if any.
+    pub greeting: Option<String>,
+    /// The language of the responses, available to the scripts.
+    #[serde(default = "default_language")]
+    pub language: String,
+    /// Scales the cooldowns of every command, between 0 and 100.
+    #[serde(default = "default_cooldown_multiplier")]
+    pub cooldown_multiplier: f64,
+    /// The commands (and their subcommands) disabled in every channel.
+    #[serde(default)]
+    pub disabled_commands: Vec<String>,
+    /// Whether to reply with the time left when a command is on cooldown instead of ignoring it.
+    /// Each user is told at most once per cooldown.
+    #[serde(default)]
+    pub cooldown_reply: bool,
+    /// The file with the per-user permission overrides.
+    #[serde(default = "default_permissions_file")]
+    pub permissions_file: String,
+    /// The channel list, persisted once channels are joined or left from chat.
+    /// `channels` only seeds it on the first start.
+    #[serde(default = "default_channels_file")]
+    pub channels_file: String,
+    /// The SQLite database backing the `store` global of the scripts.
+    #[serde(default = "default_store_file")]
+    pub store_file: String,
+    /// Whether to reload `commands.json` and the scripts automatically when they change on disk.
+    #[serde(default)]
+    pub hot_reload: bool,
+    /// How long to wait for the writes to settle before reloading a changed file.
+    #[serde(default = "default_hot_reload_debounce_ms")]
+    pub hot_reload_debounce_ms: u64,
+    /// How many regular commands may run at once in a channel, the rest wait for their turn.
+    #[serde(default = "default_max_concurrent")]
+    pub max_concurrent: usize,
+    /// Whether the replies in a channel are sent in the order of the commands, rather than
+    /// as soon as each command finishes.
+    #[serde(default = "default_ordered_replies")]
+    pub ordered_replies: bool,
+    /// Whether to append an invisible marker to a message identical to the previous one,
+    /// which Twitch's duplicate message filter (and r9k mode) would drop otherwise.
+    #[serde(default = "default_r9k_bypass")]
+    pub r9k_bypass: bool,
+    /// The marker appended to the repeated messages.
+    #[serde(default = "default_r9k_marker")]
+    pub r9k_marker: String,
+    /// How long a command may run in seconds before it's cancelled, `0` = forever.
+    /// Overridable per command.
+    #[serde(default = "default_command_timeout")]
+    pub command_timeout: u64,
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+    #[serde(default)]
+    pub workers: WorkerConfig,
+    #[serde(default)]
+    pub outbox: OutboxConfig,
+    /// Per-channel overrides of the settings above, e.g. `[channel.supinic]`.
+    /// See `profiles` for how they're resolved.
+    #[serde(default)]
+    pub channel: HashMap<String, ChannelConfig>,
+}
+
+/// The settings of a channel. Missing values fall back to the global ones.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct ChannelConfig {
+    pub prefixes: Option<Vec<String>>,
+    pub mention: Option<bool>,
+    pub max_concurrent: Option<usize>,
+    pub ordered_replies: Option<bool>,
+    pub r9k_bypass: Option<bool>,
+    /// The message sent once the bot is ready, `""` for none.
+    pub greeting: Option<String>,
+    pub language: Option<String>,
+    pub cooldown_multiplier: Option<f64>,
+    /// If set, only these commands (and their subcommands) can be used in the channel.
+    pub enabled: Option<Vec<String>>,
+    /// Disabled in addition to the global `disabled_commands`.
+    pub disabled: Option<Vec<String>>,
+    /// The StreamElements channel id of the channel, used by `api:streamelements(channel)`.
+    pub streamelements_id: Option<String>,
+    /// The users with broadcaster rights in this channel only, editable by the bot admins.
+    pub staff: Option<Vec<String>>,
+}
+
+impl ChannelConfig {
+    /// Fills the missing values from the given fallback.
+    pub fn or(&self, fallback: &ChannelConfig) -> ChannelConfig {
+        ChannelConfig {
+            prefixes: self.prefixes.clone().or_else(|| fallback.prefixes.clone()),
+            mention: self.mention.or(fallback.mention),
+            max_concurrent: self.max_concurrent.or(fallback.max_concurrent),
+            ordered_replies: self.ordered_replies.or(fallback.ordered_replies),
+            r9k_bypass: self.r9k_bypass.or(fallback.r9k_bypass),
+            greeting: self.greeting.clone().or_else(|| fallback.greeting.clone()),
+            language: self.language.clone().or_else(|| fallback.language.clone()),
+            cooldown_multiplier: self.cooldown_multiplier.or(fallback.cooldown_multiplier),
+            enabled: self.enabled.clone().or_else(|| fallback.enabled.clone()),
+            disabled: self.disabled.clone().or_else(|| fallback.disabled.clone()),
+            streamelements_id: self
+                .streamelements_id
+                .clone()
+                .or_else(|| fallback.streamelements_id.clone()),
+            staff: self.staff.clone().or_else(|| fallback.staff.clone()),
+        }
+    }
+}
+
+/// The global script limits, e.g. `[sandbox]`.
+#[derive(Debug, Deserialize)]
+pub struct SandboxConfig {
+    /// The instructions an invocation may execute in total. Overridable per command.
+    #[serde(default = "default_instruction_limit")]
+    pub instruction_limit: u64,
+    /// The memory cap of each Lua state in bytes, shared by all of its scripts, 0 = unlimited.
+    #[serde(default = "default_memory_limit")]
+    pub memory_limit: usize,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig {
+            instruction_limit: default_instruction_limit(),
+            memory_limit: default_memory_limit(),
+        }
+    }
+}
+
+/// The pool running the expensive commands, e.g. `[workers]`.
+#[derive(Debug, Deserialize)]
+pub struct WorkerConfig {
+    /// The number of worker threads, each with its own Lua state.
+    #[serde(default = "default_worker_count")]
+    pub size: usize,
+    /// How many invocations may wait for a worker before new ones are rejected.
+    #[serde(default = "default_queue_length")]
+    pub queue_length: usize,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig {
+            size: default_worker_count(),
+            queue_length: default_queue_length(),
+        }
+    }
+}
+
+/// The outgoing message queue, e.g. `[outbox]`.
+#[derive(Debug, Deserialize)]
+pub struct OutboxConfig {
+    /// How long a message may wait for its turn in seconds before it's dropped.
+    #[serde(default = "default_stale_after")]
+    pub stale_after: u64,
+    /// How many messages may wait per channel, the oldest ones are dropped first.
+    #[serde(default = "default_max_queue")]
+    pub max_queue: usize,
+    /// The prefix of the parts of a response too long for a single message.
+    #[serde(default)]
+    pub continuation: String,
+    /// How many messages a single response may be split into, the rest is dropped.
+    #[serde(default = "default_max_parts")]
+    pub max_parts: usize,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            stale_after: default_stale_after(),
+            max_queue: default_max_queue(),
+            continuation: String::new(),
+            max_parts: default_max_parts(),
+        }
+    }
+}
+
+/// The resolved dispatch settings of a channel.
+#[derive(Debug, Clone, Copy)]
+pub struct Concurrency {
+    pub limit: usize,
+    pub ordered: bool,
+}
+
+/// The resolved command trigger of a channel.
+#[derive(Debug, Clone)]
+pub struct Prefix {
+    pub prefixes: Vec<String>,
+    pub mention: bool,
+}
+
+fn default_permissions_file() -> String {
+    "permissions.json".to_owned()
+}
+
+fn default_channels_file() -> String {
+    "channels.json".to_owned()
+}
+
+fn default_store_file() -> String {
+    "anikibot.db".to_owned()
+}
+
+fn default_hot_reload_debounce_ms() -> u64 {
+    500
+}
+
+fn default_max_concurrent() -> usize {
+    3
+}
+
+fn default_ordered_replies() -> bool {
+    true
+}
+
+fn default_r9k_bypass() -> bool {
+    true
+}
+
+fn default_r9k_marker() -> String {
+    // A space followed by a tag space, which Twitch doesn't render
+    " \u{E0000}".to_owned()
+}
+
+fn default_command_timeout() -> u64 {
+    30
+}
+
+fn default_instruction_limit() -> u64 {
+    10_000_000
+}
+
+fn default_memory_limit() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_worker_count() -> usize {
+    4
+}
+
+fn default_queue_length() -> usize {
+    32
+}
+
+fn default_stale_after() -> u64 {
+    30
+}
+
+fn default_max_queue() -> usize {
+    10
+}
+
+fn default_max_parts() -> usize {
+    3
+}
+
+fn default_language() -> String {
+    "en".to_owned()
+}
+
+fn default_cooldown_multiplier() -> f64 {
+    1.0
+}
+
+fn default_prefixes() -> Vec<String> {
+    vec!["xD".to_owned()]
 }
 
 impl BotConfig {
@@ -17,4 +286,27 @@ impl BotConfig {
             Ok(config) => config,
         }
     }
+
+    /// The global settings, as the fallback of the channel settings.
+    pub fn defaults(&self) -> ChannelConfig {
+        ChannelConfig {
+            prefixes: Some(self.prefixes.clone()),
+            mention: Some(self.mention),
+            max_concurrent: Some(self.max_concurrent),
+            ordered_replies: Some(self.ordered_replies),
+            r9k_bypass: Some(self.r9k_bypass),
+            greeting: self.greeting.clone(),
+            language: Some(self.language.clone()),
+            cooldown_multiplier: Some(self.cooldown_multiplier),
+            enabled: None,
+            disabled: Some(self.disabled_commands.clone()),
+            streamelements_id: None,
+            staff: None,
+        }
+    }
+}
+
+/// Normalizes a channel name (`#Channel` -> `channel`) for config lookups.
+pub fn channel_key(channel: &str) -> String {
+    channel.trim_start_matches('#').to_lowercase()
 }
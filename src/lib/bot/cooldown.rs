@@ -0,0 +1,112 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The longest cooldown in seconds (a year), longer ones are cut to it.
+const MAX_COOLDOWN: u64 = 365 * 24 * 60 * 60;
+
+/// The cooldowns of a command in seconds, as declared in `commands.json`.
+/// Missing values are inherited from the parent command; `0` disables an inherited cooldown.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct Cooldown {
+    pub global: Option<u64>,
+    pub user: Option<u64>,
+    pub channel: Option<u64>,
+}
+
+impl Cooldown {
+    /// Fills the missing values from the parent's cooldown.
+    pub fn inherit(self, parent: Cooldown) -> Cooldown {
+        Cooldown {
+            global: self.global.or(parent.global),
+            user: self.user.or(parent.user),
+            channel: self.channel.or(parent.channel),
+        }
+    }
+
+    /// Scales the cooldowns, e.g. by a channel's multiplier.
+    pub fn scaled(self, multiplier: f64) -> Cooldown {
+        let scale = |secs: Option<u64>| {
+            secs.map(|secs| (secs as f64 * multiplier).round().min(MAX_COOLDOWN as f64) as u64)
+        };
+        Cooldown {
+            global: scale(self.global),
+            user: scale(self.user),
+            channel: scale(self.channel),
+        }
+    }
+
+    fn scopes(&self, channel: &str, user: &str) -> Vec<(Scope, Duration)> {
+        let scopes = vec![
+            (Scope::Global, self.global),
+            (Scope::Channel(channel.to_owned()), self.channel),
+            (Scope::User(channel.to_owned(), user.to_owned()), self.user),
+        ];
+        scopes
+            .into_iter()
+            .filter_map(|(scope, secs)| match secs {
+                Some(secs) if secs > 0 => {
+                    Some((scope, Duration::from_secs(secs.min(MAX_COOLDOWN))))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Scope {
+    Global,
+    Channel(String),
+    /// A user's cooldown only applies in the channel they used the command in.
+    User(String, String),
+}
+
+/// Tracks when the commands come off cooldown.
+#[derive(Debug, Default)]
+pub struct Cooldowns {
+    expires: HashMap<(String, Scope), Instant>,
+    /// When the users who were told about a cooldown can be told again, by command, channel and user.
+    notified: HashMap<(String, String, String), Instant>,
+}
+
+impl Cooldowns {
+    /// Returns the time left if the command is still on cooldown for the given channel and user.
+    pub fn remaining(
+        &self,
+        command: &str,
+        cooldown: &Cooldown,
+        channel: &str,
+        user: &str,
+    ) -> Option<Duration> {
+        let now = Instant::now();
+        cooldown
+            .scopes(channel, user)
+            .into_iter()
+            .filter_map(|(scope, _)| self.expires.get(&(command.to_owned(), scope)))
+            .filter(|expires| **expires > now)
+            .map(|expires| *expires - now)
+            .max()
+    }
+
+    /// Puts the command on cooldown for the given channel and user.
+    pub fn trigger(&mut self, command: &str, cooldown: &Cooldown, channel: &str, user: &str) {
+        let now = Instant::now();
+        self.expires.retain(|_, expires| *expires > now);
+        for (scope, duration) in cooldown.scopes(channel, user) {
+            self.expires.insert((command.to_owned(), scope), now + duration);
+        }
+    }
+
+    /// Whether the user should be told that the command is on cooldown, at most once per cooldown.
+    pub fn notify(&mut self, command: &str, channel: &str, user: &str, left: Duration) -> bool {
+        let now = Instant::now();
+        self.notified.retain(|_, until| *until > now);
+        let key = (command.to_owned(), channel.to_owned(), user.to_owned());
+        if self.notified.contains_key(&key) {
+            return false;
+        }
+        self.notified.insert(key, now + left);
+        true
+    }
+}
@@ -0,0 +1,208 @@
+//! Cron expressions, `minute hour day-of-month month day-of-week` in UTC.
+//!
+//! Every field is a comma-separated list of `*`, a value, or a range `a-b`, each optionally
+//! stepped with `/n`, e.g. `*/15 9-17 * * 1-5` is every 15 minutes during office hours.
+//! Sunday is both `0` and `7`. Like in cron, when both the day of the month and the day of
+//! the week are restricted, a day matching either of them matches.
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use std::str::FromStr;
+
+use crate::BackendError;
+
+/// How far ahead to look for the next match, so that `0 0 31 2 *` doesn't loop forever.
+const MAX_LOOKAHEAD_DAYS: i64 = 5 * 366;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    source: String,
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl FromStr for Cron {
+    type Err = BackendError;
+
+    fn from_str(source: &str) -> Result<Cron, BackendError> {
+        let fields = source.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(BackendError::from(format!(
+                "`{}` isn't a cron expression, expected 5 fields: minute hour day month weekday",
+                source
+            )));
+        }
+        let parse = |i: usize, min: usize, max: usize| {
+            parse_field(fields[i], min, max).map_err(|e| {
+                BackendError::from(format!("Invalid cron field `{}`: {}", fields[i], e))
+            })
+        };
+        let mut weekdays = parse(4, 0, 7)?;
+        // Both 0 and 7 are Sunday
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        weekdays.truncate(7);
+
+        Ok(Cron {
+            source: source.to_owned(),
+            minutes: parse(0, 0, 59)?,
+            hours: parse(1, 0, 23)?,
+            days: parse(2, 1, 31)?,
+            months: parse(3, 1, 12)?,
+            weekdays,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+}
+
+impl std::fmt::Display for Cron {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Cron {
+    /// The first matching minute strictly after the given time.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut time = after.date().and_hms(after.hour(), after.minute(), 0) + Duration::minutes(1);
+        let end = time + Duration::days(MAX_LOOKAHEAD_DAYS);
+        while time < end {
+            if !self.months[time.month() as usize] {
+                let (year, month) = match time.month() {
+                    12 => (time.year() + 1, 1),
+                    month => (time.year(), month + 1),
+                };
+                time = Utc.ymd(year, month, 1).and_hms(0, 0, 0);
+            } else if !self.day_matches(time) {
+                time = time.date().succ().and_hms(0, 0, 0);
+            } else if !self.hours[time.hour() as usize] {
+                time = time.date().and_hms(time.hour(), 0, 0) + Duration::hours(1);
+            } else if !self.minutes[time.minute() as usize] {
+                time = time + Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, time: DateTime<Utc>) -> bool {
+        let day = self.days[time.day() as usize];
+        let weekday = self.weekdays[time.weekday().num_days_from_sunday() as usize];
+        if self.days_restricted && self.weekdays_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+}
+
+/// Parses a field into a table of the matching values, indexed by the value itself.
+fn parse_field(field: &str, min: usize, max: usize) -> Result<Vec<bool>, String> {
+    let mut matches = vec![false; max + 1];
+    for item in field.split(',') {
+        let mut parts = item.splitn(2, '/');
+        let range = parts.next().unwrap_or_default();
+        let step = match parts.next() {
+            Some(step) => match step.parse::<usize>() {
+                Ok(step) if step > 0 => step,
+                _ => return Err(format!("invalid step `{}`", step)),
+            },
+            None => 1,
+        };
+        let value = |value: &str| match value.parse::<usize>() {
+            Ok(value) if (min..=max).contains(&value) => Ok(value),
+            _ => Err(format!("`{}` isn't between {} and {}", value, min, max)),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(dash) = range.find('-') {
+            (value(&range[..dash])?, value(&range[dash + 1..])?)
+        } else if step > 1 {
+            // `a/n` runs from `a` to the maximum
+            (value(range)?, max)
+        } else {
+            let value = value(range)?;
+            (value, value)
+        };
+        if start > end {
+            return Err(format!("the range `{}` is backwards", range));
+        }
+        for value in (start..=end).step_by(step) {
+            matches[value] = true;
+        }
+    }
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(field: &str, min: usize, max: usize) -> Vec<usize> {
+        let matches = parse_field(field, min, max).unwrap();
+        (0..matches.len()).filter(|&i| matches[i]).collect()
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(2020, 1, day).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn parse_field_steps_and_ranges() {
+        assert_eq!(values("*/15", 0, 59), vec![0, 15, 30, 45]);
+        assert_eq!(values("1-5", 0, 7), vec![1, 2, 3, 4, 5]);
+        assert_eq!(values("1-10/3", 0, 59), vec![1, 4, 7, 10]);
+        assert_eq!(values("40/10", 0, 59), vec![40, 50]);
+        assert_eq!(values("1,3,3", 1, 12), vec![1, 3]);
+    }
+
+    #[test]
+    fn parse_field_rejects_invalid_values() {
+        assert!(parse_field("60", 0, 59).is_err());
+        assert!(parse_field("0", 1, 31).is_err());
+        assert!(parse_field("5-1", 0, 59).is_err());
+        assert!(parse_field("*/0", 0, 59).is_err());
+        assert!(parse_field("a", 0, 59).is_err());
+        assert!("* * * *".parse::<Cron>().is_err());
+    }
+
+    #[test]
+    fn next_after_is_strictly_after() {
+        let cron = "*/15 * * * *".parse::<Cron>().unwrap();
+        assert_eq!(cron.next_after(at(1, 12, 7)), Some(at(1, 12, 15)));
+        assert_eq!(cron.next_after(at(1, 12, 15)), Some(at(1, 12, 30)));
+        assert_eq!(cron.next_after(at(1, 23, 45)), Some(at(2, 0, 0)));
+    }
+
+    #[test]
+    fn sunday_is_both_0_and_7() {
+        // 2020-01-04 is a Saturday
+        for cron in &["0 0 * * 0", "0 0 * * 7"] {
+            let cron = cron.parse::<Cron>().unwrap();
+            assert_eq!(cron.next_after(at(4, 12, 0)), Some(at(5, 0, 0)));
+        }
+    }
+
+    #[test]
+    fn restricted_days_match_either_field() {
+        // The 13th or any Friday, 2020-01-03 is a Friday
+        let cron = "0 0 13 * 5".parse::<Cron>().unwrap();
+        assert_eq!(cron.next_after(at(1, 0, 0)), Some(at(3, 0, 0)));
+        assert_eq!(cron.next_after(at(10, 0, 0)), Some(at(13, 0, 0)));
+        // Only the day of the month is restricted
+        let cron = "0 0 13 * *".parse::<Cron>().unwrap();
+        assert_eq!(cron.next_after(at(1, 0, 0)), Some(at(13, 0, 0)));
+    }
+
+    #[test]
+    fn impossible_dates_give_up_after_the_lookahead() {
+        let cron = "0 0 31 2 *".parse::<Cron>().unwrap();
+        assert_eq!(cron.next_after(at(1, 0, 0)), None);
+    }
+}
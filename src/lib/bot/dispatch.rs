@@ -0,0 +1,137 @@
+//! Runs the regular commands concurrently on the main Lua state.
+//!
+//! The command futures are polled by the bot's event loop, so they never leave the thread of the
+//! main Lua state, but a slow script no longer holds up the messages behind it. Each channel
+//! runs a limited number of commands at once, and can have its replies sent in order.
+use futures::future::{FutureExt, LocalBoxFuture};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+
+use super::config::Concurrency;
+use super::outbox::Destination;
+
+/// How many commands may wait for a free slot in a channel before new ones are dropped.
+const MAX_BACKLOG: usize = 16;
+
+/// A response along with where it goes.
+pub type Reply = (Destination, String);
+
+/// A command that ran to completion.
+pub struct Finished {
+    /// The channel the command was invoked from.
+    pub channel: String,
+    seq: u64,
+    reply: Option<Reply>,
+}
+
+#[derive(Default)]
+struct ChannelQueue<'lua> {
+    running: usize,
+    backlog: VecDeque<(u64, LocalBoxFuture<'lua, Option<Reply>>)>,
+    next_seq: u64,
+    /// The sequence number of the next reply to send, in the ordered mode.
+    next_reply: u64,
+    /// The replies waiting for the earlier commands to finish.
+    replies: BTreeMap<u64, Option<Reply>>,
+}
+
+#[derive(Default)]
+pub struct Dispatch<'lua> {
+    in_flight: FuturesUnordered<LocalBoxFuture<'lua, Finished>>,
+    channels: HashMap<String, ChannelQueue<'lua>>,
+}
+
+impl<'lua> Dispatch<'lua> {
+    /// Queues the command in the channel it was invoked from, running it right away if the
+    /// channel has a free slot. The reply goes `to` its destination, e.g. a whisper.
+    /// Returns `false` if the channel's backlog is full and the command was dropped.
+    pub fn spawn<F>(&mut self, channel: &str, to: Destination, limit: usize, future: F) -> bool
+    where
+        F: Future<Output = Option<String>> + 'lua,
+    {
+        let channel = channel.to_owned();
+        let queue = self.channels.entry(channel.clone()).or_default();
+        if queue.running >= limit && queue.backlog.len() >= MAX_BACKLOG {
+            return false;
+        }
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+
+        let future = future.map(|reply| reply.map(|text| (to, text))).boxed_local();
+        if queue.running < limit {
+            queue.running += 1;
+            self.in_flight.push(track(channel, seq, future));
+        } else {
+            queue.backlog.push_back((seq, future));
+        }
+        true
+    }
+
+    /// Waits for the next command to finish. Resolves to `None` right away if nothing is running.
+    pub async fn next(&mut self) -> Option<Finished> {
+        self.in_flight.next().await
+    }
+
+    /// Records the finished command, starts the commands waiting for its slot,
+    /// and returns the replies of its channel that can be sent now.
+    pub fn complete(&mut self, finished: Finished, concurrency: Concurrency) -> Vec<Reply> {
+        let Finished {
+            channel,
+            seq,
+            reply,
+        } = finished;
+        let queue = match self.channels.get_mut(&channel) {
+            Some(queue) => queue,
+            None => return reply.into_iter().collect(),
+        };
+
+        queue.running -= 1;
+        while queue.running < concurrency.limit {
+            match queue.backlog.pop_front() {
+                Some((seq, future)) => {
+                    queue.running += 1;
+                    self.in_flight.push(track(channel.clone(), seq, future));
+                }
+                None => break,
+            }
+        }
+
+        let mut ready = Vec::new();
+        // Commands that finished after their turn was skipped go out right away
+        if !concurrency.ordered || seq < queue.next_reply {
+            ready.extend(reply);
+            queue.next_reply = std::cmp::max(queue.next_reply, seq + 1);
+            // Don't hold back the replies queued before the channel became unordered
+            if !concurrency.ordered {
+                ready.extend(std::mem::take(&mut queue.replies).into_iter().flat_map(|(_, r)| r));
+            }
+        } else {
+            queue.replies.insert(seq, reply);
+            while let Some(reply) = queue.replies.remove(&queue.next_reply) {
+                queue.next_reply += 1;
+                ready.extend(reply);
+            }
+        }
+
+        if queue.running == 0 && queue.backlog.is_empty() && queue.replies.is_empty() {
+            self.channels.remove(&channel);
+        }
+        ready
+    }
+}
+
+fn track<'lua>(
+    channel: String,
+    seq: u64,
+    future: LocalBoxFuture<'lua, Option<Reply>>,
+) -> LocalBoxFuture<'lua, Finished> {
+    async move {
+        Finished {
+            reply: future.await,
+            channel,
+            seq,
+        }
+    }
+    .boxed_local()
+}
@@ -0,0 +1,119 @@
+//! The Twitch emotes of the chat messages, from their `emotes` tag,
+//! e.g. `25:0-4,12-16/1902:6-10` for `Kappa Keepo Kappa`.
+use std::collections::HashSet;
+
+use mlua::ToLua;
+
+/// An emote in a message. The positions are 1-based inclusive byte positions,
+/// so that `message:sub(emote.start, emote["end"])` is the emote in Lua.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Emote {
+    pub id: String,
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Parses the `emotes` tag of the message, ordered by position. Malformed ranges are skipped.
+/// Twitch counts the positions in characters, from 0.
+pub fn parse(tag: &str, message: &str) -> Vec<Emote> {
+    let chars = message.char_indices().collect::<Vec<_>>();
+    let mut emotes = Vec::new();
+    for emote in tag.split('/') {
+        let mut parts = emote.splitn(2, ':');
+        let (id, ranges) = match (parts.next(), parts.next()) {
+            (Some(id), Some(ranges)) if !id.is_empty() => (id, ranges),
+            _ => continue,
+        };
+        for range in ranges.split(',') {
+            let mut bounds = range
+                .splitn(2, '-')
+                .map(|bound| bound.parse::<usize>().ok());
+            let (start, end) = match (bounds.next().flatten(), bounds.next().flatten()) {
+                (Some(start), Some(end)) if start <= end && end < chars.len() => (start, end),
+                _ => continue,
+            };
+            let start = chars[start].0;
+            let end = chars[end].0 + chars[end].1.len_utf8();
+            emotes.push(Emote {
+                id: id.to_owned(),
+                name: message[start..end].to_owned(),
+                start: start + 1,
+                end,
+            });
+        }
+    }
+    emotes.sort_by_key(|emote| emote.start);
+    emotes
+}
+
+/// Removes the words that are one of the given emotes.
+pub fn strip(text: &str, names: &HashSet<String>) -> String {
+    text.split_whitespace()
+        .filter(|word| !names.contains(*word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl<'lua> ToLua<'lua> for Emote {
+    fn to_lua(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
+        let table = lua.create_table()?;
+        table.set("id", self.id)?;
+        table.set("name", self.name)?;
+        table.set("start", self.start)?;
+        table.set("end", self.end)?;
+        Ok(mlua::Value::Table(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emote(id: &str, name: &str, start: usize, end: usize) -> Emote {
+        Emote {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn parse_orders_the_emotes_by_position() {
+        assert_eq!(
+            parse("25:0-4,12-16/1902:6-10", "Kappa Keepo Kappa"),
+            vec![
+                emote("25", "Kappa", 1, 5),
+                emote("1902", "Keepo", 7, 11),
+                emote("25", "Kappa", 13, 17),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_converts_characters_to_bytes() {
+        let message = "héé Kappa";
+        let emotes = parse("25:4-8", message);
+        assert_eq!(emotes, vec![emote("25", "Kappa", 7, 11)]);
+        assert_eq!(&message[emotes[0].start - 1..emotes[0].end], "Kappa");
+    }
+
+    #[test]
+    fn parse_skips_malformed_ranges() {
+        assert!(parse("", "Kappa").is_empty());
+        assert!(parse("25", "Kappa").is_empty());
+        assert!(parse(":0-4", "Kappa").is_empty());
+        assert!(parse("25:4-0", "Kappa").is_empty());
+        assert!(parse("25:0-5", "Kappa").is_empty());
+        assert!(parse("25:a-b", "Kappa").is_empty());
+        assert_eq!(parse("25:x,0-4", "Kappa"), vec![emote("25", "Kappa", 1, 5)]);
+    }
+
+    #[test]
+    fn strip_removes_only_the_given_names() {
+        let names = ["Kappa".to_owned()].iter().cloned().collect::<HashSet<_>>();
+        assert_eq!(strip("Kappa  forsen Kappa", &names), "forsen");
+        assert_eq!(strip("KappaPride", &names), "KappaPride");
+    }
+}
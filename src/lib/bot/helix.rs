@@ -0,0 +1,112 @@
+//! A client for the parts of Twitch's Helix API the bot uses: the live status of the channels
+//! and the whispers, which Twitch no longer takes over chat.
+//!
+//! Helix needs the client id of the app the chat token was generated for, `twitch_client_id`
+//! in `secrets.toml`. Whispering also needs the `user:manage:whispers` scope on the token.
+use reqwest::Client;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use super::config::channel_key;
+use crate::BackendError;
+
+const HELIX_URL: &str = "https://api.twitch.tv/helix";
+
+#[derive(Deserialize)]
+struct Data<T> {
+    data: Vec<T>,
+}
+
+#[derive(Deserialize)]
+struct User {
+    id: String,
+}
+
+#[derive(Clone)]
+pub struct Helix {
+    client: Client,
+    client_id: String,
+    /// The token, without the `oauth:` prefix.
+    token: String,
+    /// The user ids by login, the bot's own under `""`.
+    ids: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Helix {
+    pub fn new(client_id: String, token: &str) -> Helix {
+        Helix {
+            client: Client::new(),
+            client_id,
+            token: token.trim_start_matches("oauth:").to_owned(),
+            ids: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Checks whether the channel is streaming.
+    pub async fn is_live(&self, channel: &str) -> Result<bool, BackendError> {
+        let streams = self
+            .client
+            .get(&format!("{}/streams", HELIX_URL))
+            .query(&[("user_login", channel_key(channel))])
+            .header("Client-ID", &self.client_id)
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Data<serde_json::Value>>()
+            .await?;
+        Ok(!streams.data.is_empty())
+    }
+
+    /// Whispers the user from the account of the token.
+    pub async fn whisper(&self, user: &str, message: &str) -> Result<(), BackendError> {
+        let from = self.user_id(None).await?;
+        let to = self.user_id(Some(user)).await?;
+        self.client
+            .post(&format!("{}/whispers", HELIX_URL))
+            .query(&[("from_user_id", from), ("to_user_id", to)])
+            .header("Client-ID", &self.client_id)
+            .bearer_auth(&self.token)
+            .json(&serde_json::json!({ "message": message }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Looks up the id of the user, or of the token's account with `None`.
+    async fn user_id(&self, login: Option<&str>) -> Result<String, BackendError> {
+        let key = login.map(str::to_lowercase).unwrap_or_default();
+        // Not held across the request, the future has to be `Send`
+        let cached = self.ids.lock().unwrap().get(&key).cloned();
+        if let Some(id) = cached {
+            return Ok(id);
+        }
+        let mut request = self
+            .client
+            .get(&format!("{}/users", HELIX_URL))
+            .header("Client-ID", &self.client_id)
+            .bearer_auth(&self.token);
+        if login.is_some() {
+            request = request.query(&[("login", &key)]);
+        }
+        let users = request
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Data<User>>()
+            .await?;
+        let id = match users.data.into_iter().next() {
+            Some(user) => user.id,
+            None => {
+                return Err(BackendError::from(format!(
+                    "No Twitch user named `{}`",
+                    key
+                )))
+            }
+        };
+        self.ids.lock().unwrap().insert(key, id.clone());
+        Ok(id)
+    }
+}
@@ -0,0 +1,346 @@
+//! Scripts reacting to the chat events other than commands, declared in `hooks.json`:
+//!
+//! ```json
+//! {
+//!     "raid": [{ "script": "scripts/hooks/raid.lua", "channels": ["moscowwbish"] }],
+//!     "clearchat": [{ "script": "scripts/hooks/modlog.lua" }]
+//! }
+//! ```
+//!
+//! A hook receives a single table describing the event, see `Event`. If it returns a string,
+//! it's sent to the channel of the event. Its errors are only logged.
+use mlua::ToLua;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use twitchchat::messages::AllCommands;
+
+use super::command::args::ArgValue;
+use super::command::compile;
+use super::command::report::{LoadError, LoadErrorKind, LoadReport};
+use super::config::channel_key;
+use crate::lua::sandbox::Sandbox;
+
+/// The event kinds a hook can be declared for.
+pub const KINDS: &[&str] = &[
+    "sub",
+    "resub",
+    "subgift",
+    "submysterygift",
+    "raid",
+    "usernotice",
+    "clearchat",
+    "clearmsg",
+    "join",
+    "part",
+    "roomstate",
+    "whisper",
+];
+
+#[derive(Deserialize)]
+struct HookJSON {
+    script: String,
+    /// The channels the hook runs in, every channel if missing. Ignored for whispers.
+    channels: Option<Vec<String>>,
+    sandbox: Option<Sandbox>,
+    timeout: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct Hook<'lua> {
+    /// The name of the hook in the logs and in `running`, e.g. `hook:raid/scripts/raid.lua`.
+    /// Every script of a hook gets its own `store`, apart from the ones of the commands.
+    pub id: String,
+    pub path: String,
+    channels: Option<HashSet<String>>,
+    pub sandbox: Sandbox,
+    /// How long the script may run in seconds, overriding the global timeout. `0` disables it.
+    pub timeout: Option<u64>,
+    pub script: mlua::Function<'lua>,
+}
+
+/// The hooks by event kind.
+#[derive(Clone, Default)]
+pub struct Hooks<'lua> {
+    hooks: HashMap<String, Vec<Hook<'lua>>>,
+}
+
+impl<'lua> Hooks<'lua> {
+    /// Loads the hooks file, collecting every error into the report like `load_commands`.
+    /// A missing file means no hooks. When reloading, the broken hooks keep their `previous`
+    /// working versions, and a broken hooks file keeps all of them.
+    pub fn load(
+        lua: &'lua mlua::Lua,
+        path: &str,
+        previous: Option<&Hooks<'lua>>,
+    ) -> (Hooks<'lua>, LoadReport) {
+        let mut report = LoadReport::default();
+        let json = match std::fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return (Hooks::default(), report)
+            }
+            Err(e) => {
+                report.errors.push(
+                    LoadError::new(LoadErrorKind::MissingFile, e.to_string()).file(path),
+                );
+                return (previous.cloned().unwrap_or_default(), report);
+            }
+        };
+        let declared = match serde_json::from_str::<HashMap<String, Vec<HookJSON>>>(&json) {
+            Ok(declared) => declared,
+            Err(e) => {
+                report
+                    .errors
+                    .push(LoadError::new(LoadErrorKind::Schema, e.to_string()).file(path));
+                return (previous.cloned().unwrap_or_default(), report);
+            }
+        };
+
+        let mut hooks = HashMap::new();
+        for (kind, declared) in declared {
+            if !KINDS.contains(&kind.as_str()) {
+                report.errors.push(
+                    LoadError::new(
+                        LoadErrorKind::Schema,
+                        format!("Unknown event, expected one of: {}", KINDS.join(", ")),
+                    )
+                    .command(&format!("hook:{}", kind)),
+                );
+                continue;
+            }
+            let previous = previous.and_then(|p| p.hooks.get(&kind));
+            let mut loaded = Vec::new();
+            for hook in declared {
+                let id = format!("hook:{}/{}", kind, hook.script);
+                let sandbox = hook.sandbox.unwrap_or_default();
+                let script = match compile(lua, &id, &hook.script, &sandbox) {
+                    Ok(script) => script,
+                    Err(e) => {
+                        report.errors.push(e);
+                        let kept = previous.and_then(|p| p.iter().find(|h| h.path == hook.script));
+                        if let Some(hook) = kept {
+                            report.kept.push(id.clone());
+                            loaded.push(hook.clone());
+                        }
+                        continue;
+                    }
+                };
+                loaded.push(Hook {
+                    id,
+                    path: hook.script,
+                    channels: hook
+                        .channels
+                        .map(|channels| channels.iter().map(|c| channel_key(c)).collect()),
+                    sandbox,
+                    timeout: hook.timeout,
+                    script,
+                });
+            }
+            hooks.insert(kind, loaded);
+        }
+        (Hooks { hooks }, report)
+    }
+
+    /// The hooks to run for the event.
+    pub fn matching(&self, event: &Event) -> Vec<Hook<'lua>> {
+        let channel = event.channel.as_deref().map(channel_key);
+        self.hooks
+            .get(event.kind)
+            .map(|hooks| {
+                hooks
+                    .iter()
+                    .filter(|hook| match (&hook.channels, &channel) {
+                        (Some(channels), Some(channel)) => channels.contains(channel),
+                        _ => true,
+                    })
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The scripts of every hook, for the hot reloading.
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.hooks.values().flatten().map(|hook| hook.path.as_str())
+    }
+
+    /// Recompiles the hooks using the changed script, keeping the previous versions on error.
+    pub fn reload_script(&mut self, lua: &'lua mlua::Lua, path: &str) {
+        for hook in self.hooks.values_mut().flatten() {
+            if hook.path != path {
+                continue;
+            }
+            match compile(lua, &hook.id, &hook.path, &hook.sandbox) {
+                Ok(script) => {
+                    hook.script = script;
+                    log::info!("Hot reloaded `{}`", hook.id);
+                }
+                Err(e) => log::error!("Failed to hot reload, keeping the previous version: {}", e),
+            }
+        }
+    }
+}
+
+/// A chat event, passed to the hooks as a table with the `kind`, `channel`, `user` and
+/// `message` of the event (the latter three when they apply), along with the fields
+/// specific to the kind, e.g. `months` of a `resub` or `duration` of a `clearchat`.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub kind: &'static str,
+    /// The channel, with the `#`. `None` for whispers.
+    pub channel: Option<String>,
+    pub user: Option<String>,
+    pub message: Option<String>,
+    pub fields: BTreeMap<&'static str, ArgValue>,
+}
+
+impl Event {
+    fn new(kind: &'static str, channel: Option<&str>) -> Event {
+        Event {
+            kind,
+            channel: channel.map(str::to_owned),
+            user: None,
+            message: None,
+            fields: BTreeMap::new(),
+        }
+    }
+
+    fn set(&mut self, name: &'static str, value: Option<ArgValue>) {
+        if let Some(value) = value {
+            self.fields.insert(name, value);
+        }
+    }
+
+    /// Builds the event from a message, if it's one the hooks can react to.
+    pub fn parse(message: &AllCommands<'_>) -> Option<Event> {
+        let event = match message {
+            AllCommands::UserNotice(notice) => {
+                let tag = |name: &str| notice.tags.get(name).map(|value| value.to_string());
+                let number = |name: &str| tag(name).and_then(|v| v.parse().ok()).map(ArgValue::Int);
+                let text = |name: &str| tag(name).map(ArgValue::Str);
+
+                let id = tag("msg-id").unwrap_or_default();
+                let kind = match id.as_str() {
+                    "sub" => "sub",
+                    "resub" => "resub",
+                    "subgift" | "anonsubgift" => "subgift",
+                    "submysterygift" | "anonsubmysterygift" => "submysterygift",
+                    "raid" => "raid",
+                    _ => "usernotice",
+                };
+                let mut event = Event::new(kind, Some(&notice.channel));
+                event.user = tag("login");
+                event.message = notice.message.as_ref().map(|message| message.to_string());
+                event.set("id", Some(ArgValue::Str(id)));
+                event.set("display_name", text("display-name"));
+                event.set("system_message", text("system-msg"));
+                event.set("months", number("msg-param-cumulative-months"));
+                event.set("streak", number("msg-param-streak-months"));
+                event.set("plan", text("msg-param-sub-plan"));
+                event.set("recipient", text("msg-param-recipient-user-name"));
+                event.set("gifts", number("msg-param-mass-gift-count"));
+                event.set("viewers", number("msg-param-viewerCount"));
+                event
+            }
+            AllCommands::ClearChat(clear) => {
+                let duration = clear
+                    .tags
+                    .get("ban-duration")
+                    .and_then(|secs| secs.parse::<i64>().ok());
+                let mut event = Event::new("clearchat", Some(&clear.channel));
+                event.user = clear.name.as_ref().map(|name| name.to_string());
+                let action = match (&event.user, duration) {
+                    (None, _) => "clear",
+                    (Some(_), None) => "ban",
+                    (Some(_), Some(_)) => "timeout",
+                };
+                event.set("action", Some(ArgValue::Str(action.to_owned())));
+                event.set("duration", duration.map(ArgValue::Int));
+                event
+            }
+            AllCommands::ClearMsg(clear) => {
+                let tag = |name: &str| clear.tags.get(name).map(|value| value.to_string());
+                let mut event = Event::new("clearmsg", Some(&clear.channel));
+                event.user = tag("login");
+                event.message = clear.message.as_ref().map(|message| message.to_string());
+                event.set("message_id", tag("target-msg-id").map(ArgValue::Str));
+                event
+            }
+            AllCommands::Join(join) => {
+                let mut event = Event::new("join", Some(&join.channel));
+                event.user = Some(join.name.to_string());
+                event
+            }
+            AllCommands::Part(part) => {
+                let mut event = Event::new("part", Some(&part.channel));
+                event.user = Some(part.name.to_string());
+                event
+            }
+            AllCommands::RoomState(state) => {
+                // Only the changed settings are sent after the initial ROOMSTATE
+                let number = |name: &str| {
+                    state
+                        .tags
+                        .get(name)
+                        .and_then(|value| value.parse().ok())
+                        .map(ArgValue::Int)
+                };
+                let mut event = Event::new("roomstate", Some(&state.channel));
+                event.set("slow", number("slow"));
+                event.set("followers_only", number("followers-only"));
+                event.set("emote_only", number("emote-only").map(is_on));
+                event.set("subs_only", number("subs-only").map(is_on));
+                event.set("r9k", number("r9k").map(is_on));
+                event
+            }
+            // twitchchat has no type for whispers
+            AllCommands::Unknown(raw) if &*raw.command == "WHISPER" => {
+                let mut event = Event::new("whisper", None);
+                event.user = sender(&raw.raw);
+                event.message = raw.data.as_ref().map(|data| data.to_string());
+                event.set(
+                    "display_name",
+                    raw.tags
+                        .get("display-name")
+                        .map(|name| ArgValue::Str(name.to_string())),
+                );
+                event
+            }
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+fn is_on(value: ArgValue) -> ArgValue {
+    ArgValue::Bool(value == ArgValue::Int(1))
+}
+
+/// Extracts the login from the prefix of a raw IRC line, `@tags :login!login@host COMMAND ...`.
+fn sender(line: &str) -> Option<String> {
+    let line = match line.strip_prefix('@') {
+        Some(tagged) => tagged.splitn(2, ' ').nth(1)?,
+        None => line,
+    };
+    let prefix = line.strip_prefix(':')?.split(' ').next()?;
+    let login = prefix.split('!').next()?;
+    if login.is_empty() {
+        None
+    } else {
+        Some(login.to_owned())
+    }
+}
+
+impl<'lua> ToLua<'lua> for Event {
+    fn to_lua(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
+        let table = lua.create_table()?;
+        table.set("kind", self.kind)?;
+        table.set("channel", self.channel)?;
+        table.set("user", self.user)?;
+        table.set("message", self.message)?;
+        for (name, value) in self.fields {
+            table.set(name, value)?;
+        }
+        Ok(mlua::Value::Table(table))
+    }
+}
@@ -0,0 +1,119 @@
+//! Tracks the running command invocations, so that staff can list them and cancel the stuck ones.
+//!
+//! Cancelling relies on the event loop staying responsive while a command runs: the expensive
+//! commands run on the worker threads, and the inline ones are polled by `Dispatch` alongside
+//! the chat events rather than awaited by the handler.
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+#[derive(Debug, Clone)]
+pub struct Invocation {
+    pub id: u64,
+    /// The full path of the command.
+    pub command: String,
+    pub channel: String,
+    pub user: String,
+    pub started: Instant,
+}
+
+/// Why an invocation didn't run to completion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupted {
+    TimedOut(Duration),
+    Cancelled,
+}
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    running: HashMap<u64, (Invocation, oneshot::Sender<()>)>,
+}
+
+/// The registry of running invocations, shared with the threads of the expensive commands.
+#[derive(Clone, Default)]
+pub struct Invocations {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Invocations {
+    /// Registers a new invocation. It stays listed until the returned handle is dropped.
+    pub fn start(&self, command: &str, channel: &str, user: &str) -> Running {
+        let (tx, rx) = oneshot::channel();
+        let mut registry = self.registry.lock().unwrap();
+        registry.next_id += 1;
+        let id = registry.next_id;
+        let invocation = Invocation {
+            id,
+            command: command.to_owned(),
+            channel: channel.to_owned(),
+            user: user.to_owned(),
+            started: Instant::now(),
+        };
+        registry.running.insert(id, (invocation, tx));
+
+        Running {
+            id,
+            invocations: self.clone(),
+            cancelled: rx,
+        }
+    }
+
+    /// Lists the running invocations, oldest first.
+    pub fn list(&self) -> Vec<Invocation> {
+        let registry = self.registry.lock().unwrap();
+        let mut running = registry
+            .running
+            .values()
+            .map(|(invocation, _)| invocation.clone())
+            .collect::<Vec<_>>();
+        running.sort_by_key(|invocation| invocation.id);
+        running
+    }
+
+    /// Cancels the invocation, returning it if it was still running.
+    pub fn cancel(&self, id: u64) -> Option<Invocation> {
+        let (invocation, tx) = self.registry.lock().unwrap().running.remove(&id)?;
+        // The invocation may finish at the same time, in which case there's no one to notify
+        let _ = tx.send(());
+        Some(invocation)
+    }
+}
+
+/// The handle of a running invocation.
+pub struct Running {
+    id: u64,
+    invocations: Invocations,
+    cancelled: oneshot::Receiver<()>,
+}
+
+impl Running {
+    /// Runs the script future until it completes, the timeout expires or the invocation is cancelled.
+    /// The script future is dropped in the latter two cases. `None` means no timeout.
+    pub async fn run<F: Future>(
+        mut self,
+        timeout: Option<Duration>,
+        future: F,
+    ) -> Result<F::Output, Interrupted> {
+        let expired = async {
+            match timeout {
+                Some(timeout) => tokio::time::delay_for(timeout).await,
+                None => futures::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            output = future => Ok(output),
+            _ = expired => Err(Interrupted::TimedOut(timeout.unwrap_or_default())),
+            _ = &mut self.cancelled => Err(Interrupted::Cancelled),
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.invocations.registry.lock().unwrap().running.remove(&self.id);
+    }
+}
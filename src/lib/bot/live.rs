@@ -0,0 +1,53 @@
+//! Whether the channels are live, from the Helix API, for the timers that only run on stream.
+//!
+//! Without Helix credentials every channel counts as live.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::config::channel_key;
+use super::helix::Helix;
+
+/// How long a status is reused before asking again.
+const CACHE_FOR: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct LiveStatus {
+    helix: Option<Helix>,
+    cache: Arc<Mutex<HashMap<String, (Instant, bool)>>>,
+}
+
+impl LiveStatus {
+    pub fn new(helix: Option<Helix>) -> LiveStatus {
+        LiveStatus {
+            helix,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Checks whether the channel is live. Counts it as live if that can't be known.
+    pub async fn is_live(&self, channel: &str) -> bool {
+        let channel = channel_key(channel);
+        if let Some((checked, live)) = self.cache.lock().unwrap().get(&channel) {
+            if checked.elapsed() < CACHE_FOR {
+                return *live;
+            }
+        }
+        let helix = match &self.helix {
+            Some(helix) => helix,
+            None => return true,
+        };
+        let live = match helix.is_live(&channel).await {
+            Ok(live) => live,
+            Err(e) => {
+                log::error!("Failed to check whether {} is live: {}", channel, e);
+                return true;
+            }
+        };
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(channel, (Instant::now(), live));
+        live
+    }
+}
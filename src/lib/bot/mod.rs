@@ -1,30 +1,78 @@
 #[macro_use]
 pub mod macros;
+pub mod channels;
 pub mod command;
 pub mod config;
+pub mod cooldown;
+pub mod cron;
+pub mod dispatch;
+pub mod emotes;
+pub mod helix;
+pub mod hooks;
+pub mod invocations;
+pub mod live;
+pub mod outbox;
+pub mod permissions;
+pub mod profiles;
+pub mod timers;
+pub mod watcher;
+pub mod workers;
 pub mod util;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
 
+use futures::FutureExt as _;
 use mlua::{ToLua, UserData, UserDataMethods};
 use tokio::stream::StreamExt as _;
-use twitchchat::{events, messages, Control, Dispatcher, IntoChannel};
+use twitchchat::{events, messages, Control, Dispatcher};
 
 use crate::{
-    stream_elements::consumer::ConsumerStreamElementsAPI, youtube::ConsumerYouTubePlaylistAPI,
-    BackendError,
+    lua::sandbox::{self, InstructionBudget, Sandbox},
+    lua::store::Store,
+    stream_elements::consumer::ConsumerStreamElementsAPI,
+    youtube::ConsumerYouTubePlaylistAPI, BackendError, BoxedError,
 };
+use channels::{Channels, SharedChannels};
 use command::{load_commands, Command};
+use command::report::{LoadError, LoadReport};
+use command::context::{self, Caller};
+use command::{args, help, CommandData, CommandList};
+use cooldown::Cooldowns;
+use dispatch::Dispatch;
+use helix::Helix;
+use hooks::{Event, Hooks};
+use invocations::{Interrupted, Invocations, Running};
+use live::LiveStatus;
+use outbox::{Destination, Outbox};
+use permissions::{Override, Permission, Permissions, Roles, SharedPermissions};
+use profiles::{Profile, Profiles, SharedProfiles};
+use timers::{DueAction, Schedule, Scheduler, TimerScript};
+use watcher::ScriptWatcher;
+use workers::{WorkerGlobals, WorkerPool};
+
+const COMMANDS_FILE: &str = "commands.json";
+const HOOKS_FILE: &str = "hooks.json";
+const TIMERS_FILE: &str = "timers.json";
 
 /* Previously had commands: ping, ping uptime, whoami, song, song queue */
 
 pub struct BotBuilder {
     streamelements_api: Option<ConsumerStreamElementsAPI>,
     youtube_api: Option<ConsumerYouTubePlaylistAPI>,
+    /// For the live checks of the timers and the whispers.
+    helix: Option<Helix>,
     control: Control,
 }
 
 impl BotBuilder {
+    pub fn add_helix_credentials(self, client_id: String, token: String) -> Self {
+        BotBuilder {
+            helix: Some(Helix::new(client_id, &token)),
+            ..self
+        }
+    }
+
     pub fn add_streamelements_api(self, streamelements_api: ConsumerStreamElementsAPI) -> Self {
         BotBuilder {
             streamelements_api: Some(streamelements_api),
@@ -40,17 +88,99 @@ impl BotBuilder {
     }
 
     pub fn build<'lua>(self, lua: &'lua mlua::Lua) -> Bot<'lua> {
-        let commands: HashMap<String, Command<'lua>> =
-            load_commands(lua, "commands.json").expect("Failed to load the commands");
+        let config = config::BotConfig::get();
+        let permissions =
+            Permissions::load(&config.permissions_file).expect("Failed to load the permissions");
+        let permissions = Arc::new(RwLock::new(permissions));
+        let bosses = Arc::new(config.gym_staff.clone());
+        let channels = Channels::load(&config.channels_file, &config.channels)
+            .expect("Failed to load the channels");
+        let channels = Arc::new(Mutex::new(channels));
+        let store = Store::open(&config.store_file).expect("Failed to open the store");
+        let profiles = Profiles::load(&config, &store).expect("Failed to load the profiles");
+        let profiles = Arc::new(RwLock::new(profiles));
+        let budget = InstructionBudget::install(lua, config.sandbox.memory_limit);
+        crate::lua::store::register(lua, store.clone());
+        let mut startup_report = Vec::new();
+        let (commands, report) = load_commands(lua, COMMANDS_FILE, None);
+        report.log();
+        if !report.is_ok() {
+            startup_report.extend(report.summary());
+        }
+        let (hooks, report) = Hooks::load(lua, HOOKS_FILE, None);
+        report.log();
+        if !report.is_ok() {
+            startup_report.extend(report.summary());
+        }
+        let (scheduler, timer_wake) = Scheduler::new();
+        let (timers, declared, report) = timers::load_timers(lua, TIMERS_FILE, None);
+        report.log();
+        if !report.is_ok() {
+            startup_report.extend(report.summary());
+        }
+        scheduler.set_declared(declared.unwrap_or_default());
 
-        Bot {
+        let outbox = Outbox::start(self.control.clone(), &config, self.helix.clone());
+        let start = chrono::Utc::now();
+        let command_list = Arc::new(RwLock::new(Vec::new()));
+        let workers = WorkerPool::start(
+            &config.workers,
+            config.sandbox.memory_limit,
+            WorkerGlobals {
+                store: store.clone(),
+                api: APIStorage {
+                    streamelements: self.streamelements_api.clone(),
+                    youtube_playlist: self.youtube_api.clone(),
+                    profiles: profiles.clone(),
+                },
+                bot: BotInfo {
+                    start,
+                    control: self.control.clone(),
+                    outbox: outbox.clone(),
+                    channels: channels.clone(),
+                    profiles: profiles.clone(),
+                    permissions: permissions.clone(),
+                    bosses: bosses.clone(),
+                    commands: command_list.clone(),
+                    // The functions can only be scheduled on the main Lua state
+                    scheduler: None,
+                    owner: String::new(),
+                    manage_channels: false,
+                },
+            },
+            outbox.clone(),
+        );
+
+        let bot = Bot {
             streamelements: self.streamelements_api,
             youtube_playlist: self.youtube_api,
             control: self.control,
-            config: config::BotConfig::get(),
-            start: chrono::Utc::now(),
+            outbox,
+            channels,
+            profiles,
+            config,
+            start,
+            nickname: None,
+            startup_report,
+            watcher: None,
+            cooldowns: Cooldowns::default(),
+            dispatch: Dispatch::default(),
+            invocations: Invocations::default(),
+            permissions,
+            bosses,
+            store,
+            budget,
+            workers,
+            command_list,
             commands,
-        }
+            hooks,
+            scheduler,
+            timer_wake,
+            timers,
+            live: LiveStatus::new(self.helix),
+        };
+        bot.refresh_command_list();
+        bot
     }
 }
 
@@ -58,9 +188,35 @@ pub struct Bot<'lua> {
     pub streamelements: Option<ConsumerStreamElementsAPI>,
     pub youtube_playlist: Option<ConsumerYouTubePlaylistAPI>,
     control: Control,
+    outbox: Outbox,
+    channels: SharedChannels,
+    profiles: SharedProfiles,
     config: config::BotConfig,
     pub start: chrono::DateTime<chrono::Utc>,
+    /// The bot's own name, known once the connection is ready. Used for mention-triggering.
+    nickname: Option<String>,
+    /// The summary of the errors found while loading the files at startup, posted once ready.
+    startup_report: Vec<String>,
+    watcher: Option<ScriptWatcher>,
+    cooldowns: Cooldowns,
+    /// The regular commands in flight.
+    dispatch: Dispatch<'lua>,
+    invocations: Invocations,
+    permissions: SharedPermissions,
+    /// The global staff, shared with `BotInfo`.
+    bosses: Arc<HashSet<String>>,
+    pub store: Store,
+    budget: InstructionBudget,
+    workers: WorkerPool,
+    /// The flattened command tree, shared with `BotInfo`.
+    command_list: CommandList,
     pub commands: HashMap<String, Command<'lua>>,
+    hooks: Hooks<'lua>,
+    scheduler: Scheduler,
+    timer_wake: tokio::sync::mpsc::UnboundedReceiver<()>,
+    /// The scripts of the declared timers, by name.
+    timers: HashMap<String, TimerScript<'lua>>,
+    live: LiveStatus,
 }
 
 impl<'lua> Bot<'lua> {
@@ -68,6 +224,7 @@ impl<'lua> Bot<'lua> {
         BotBuilder {
             streamelements_api: None,
             youtube_api: None,
+            helix: None,
             control,
         }
     }
@@ -76,6 +233,15 @@ impl<'lua> Bot<'lua> {
         BotInfo {
             start: self.start,
             control: self.control.clone(),
+            outbox: self.outbox.clone(),
+            channels: self.channels.clone(),
+            profiles: self.profiles.clone(),
+            permissions: self.permissions.clone(),
+            bosses: self.bosses.clone(),
+            commands: self.command_list.clone(),
+            scheduler: Some(self.scheduler.clone()),
+            owner: String::new(),
+            manage_channels: false,
         }
     }
 
@@ -83,219 +249,913 @@ impl<'lua> Bot<'lua> {
         APIStorage {
             streamelements: self.streamelements.clone(),
             youtube_playlist: self.youtube_playlist.clone(),
+            profiles: self.profiles.clone(),
         }
     }
 
     #[inline]
     pub fn is_boss(&self, name: &str) -> bool {
-        self.config.gym_staff.contains(name)
+        self.bosses.contains(name)
+    }
+
+    fn refresh_command_list(&self) {
+        let entries = util::flatten_commands(&self.commands)
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        *self.command_list.write().unwrap() = entries;
+    }
+
+    /// Checks whether the user is a global staff member or one of the channel's own.
+    pub fn is_staff(&self, name: &str, profile: &Profile) -> bool {
+        is_staff(&self.bosses, name, profile)
+    }
+
+    pub fn profile(&self, channel: &str) -> Profile {
+        self.profiles.read().unwrap().get(channel)
     }
 
-    pub async fn run(mut self, lua: &mlua::Lua, dispatcher: Dispatcher) {
+    /// Checks whether the command is enabled in the channel, its permission level
+    /// and the caller's overrides.
+    pub fn can_run(
+        &self,
+        evt: &messages::Privmsg<'_>,
+        profile: &Profile,
+        command: &CommandData<'_>,
+    ) -> bool {
+        allowed(
+            &self.permissions.read().unwrap(),
+            &self.bosses,
+            profile,
+            &evt.name,
+            Roles::of(evt),
+            &command.id,
+            command.permission,
+        )
+    }
+
+    pub async fn run(mut self, lua: &'lua mlua::Lua, dispatcher: Dispatcher) {
         let mut events = dispatcher.subscribe::<events::All>();
 
         let ready = dispatcher.wait_for::<events::IrcReady>().await.unwrap();
 
         self.join_configured_channels(&ready.nickname).await;
+        self.nickname = Some(ready.nickname.to_string());
 
-        self.send(
-            &"moscowwbish".into_channel().unwrap(),
-            "gachiHYPER I'M READY",
-        )
-        .await;
+        let channels = self.channels.lock().unwrap().list();
+        for channel in &channels {
+            if let Some(greeting) = self.profile(channel).greeting {
+                self.send(&format!("#{}", channel), greeting).await;
+            }
+        }
+        // The load errors go to the channels of the bot admins, who can fix them
+        let startup_report = std::mem::take(&mut self.startup_report);
+        let admin_channels = channels
+            .into_iter()
+            .filter(|channel| self.is_boss(channel))
+            .collect::<Vec<_>>();
+        for channel in admin_channels {
+            for response in &startup_report {
+                self.send(&format!("#{}", channel), response.as_str()).await;
+            }
+        }
 
-        while let Some(event) = events.next().await {
-            if let messages::AllCommands::Privmsg(msg) = &*event {
-                self.handle_msg(msg, lua).await;
-            };
+        if self.config.hot_reload {
+            match ScriptWatcher::new(std::time::Duration::from_millis(
+                self.config.hot_reload_debounce_ms,
+            )) {
+                Ok(watcher) => {
+                    self.watcher = Some(watcher);
+                    self.watch_scripts();
+                }
+                Err(e) => log::error!("Hot reloading is disabled: {}", e),
+            }
+        }
+
+        loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(event) => {
+                        match &*event {
+                            messages::AllCommands::Privmsg(msg) => self.handle_msg(msg, lua).await,
+                            messages::AllCommands::UserState(state) => {
+                                let badges = state
+                                    .tags
+                                    .get("badges")
+                                    .map(|badges| badges.to_string())
+                                    .unwrap_or_default();
+                                self.outbox.set_badges(&state.channel, &badges);
+                            }
+                            messages::AllCommands::RoomState(state) => {
+                                // Only the changed settings are sent after the initial ROOMSTATE
+                                let slow = state.tags.get("slow").and_then(|s| s.parse().ok());
+                                if let Some(slow) = slow {
+                                    self.outbox.set_slow_mode(&state.channel, slow);
+                                }
+                            }
+                            _ => (),
+                        }
+                        if let Some(event) = Event::parse(&event) {
+                            if event.kind == "whisper" {
+                                self.handle_whisper(&event, lua).await;
+                            }
+                            self.run_hooks(event);
+                        }
+                    }
+                    None => break,
+                },
+                Some(finished) = self.dispatch.next() => {
+                    let channel = finished.channel.clone();
+                    let concurrency = self.profile(&channel).concurrency;
+                    for (to, reply) in self.dispatch.complete(finished, concurrency) {
+                        self.deliver(&to, reply).await;
+                    }
+                }
+                Some(file) = next_change(&mut self.watcher) => self.hot_reload(lua, &file),
+                _ = timers::next_tick(&self.scheduler, &mut self.timer_wake) => self.run_timers(lua),
+            }
+        }
+    }
+
+    /// Points the watcher at the commands and hooks files and every script they reference.
+    fn watch_scripts(&mut self) {
+        let mut files = vec![
+            COMMANDS_FILE.to_owned(),
+            HOOKS_FILE.to_owned(),
+            TIMERS_FILE.to_owned(),
+        ];
+        files.extend(
+            util::flatten_commands(&self.commands)
+                .into_iter()
+                .map(|data| data.path.clone()),
+        );
+        files.extend(self.hooks.paths().map(str::to_owned));
+        files.extend(self.timers.values().map(|timer| timer.path.clone()));
+        if let Some(watcher) = self.watcher.as_mut() {
+            watcher.watch(files);
+        }
+    }
+
+    /// Reloads the commands file or every command using the changed script.
+    /// Broken scripts are logged and keep their previous working versions.
+    fn hot_reload(&mut self, lua: &'lua mlua::Lua, file: &str) {
+        if file == COMMANDS_FILE {
+            log::info!("{} changed, reloading all commands", file);
+            let (commands, report) = load_commands(lua, COMMANDS_FILE, Some(&self.commands));
+            report.log();
+            self.commands = commands;
+            self.workers.invalidate();
+            self.refresh_command_list();
+            self.watch_scripts();
+            return;
+        }
+        if file == HOOKS_FILE {
+            log::info!("{} changed, reloading all hooks", file);
+            self.reload_hooks(lua);
+            self.watch_scripts();
+            return;
         }
+        if file == TIMERS_FILE {
+            log::info!("{} changed, reloading all timers", file);
+            self.reload_timers(lua);
+            self.watch_scripts();
+            return;
+        }
+
+        log::info!(
+            "{} changed, reloading the commands, hooks and timers using it",
+            file
+        );
+        self.hooks.reload_script(lua, file);
+        for timer in self.timers.values_mut().filter(|timer| timer.path == file) {
+            match command::compile(lua, &timer.id, &timer.path, &timer.sandbox) {
+                Ok(script) => {
+                    timer.script = script;
+                    log::info!("Hot reloaded `{}`", timer.id);
+                }
+                Err(e) => log::error!("Failed to hot reload, keeping the previous version: {}", e),
+            }
+        }
+        util::visit_commands_mut(&mut self.commands, &mut |data| {
+            if data.path != file {
+                return;
+            }
+            match command::compile(lua, &data.id, &data.path, &data.sandbox) {
+                Ok(script) => {
+                    data.script = script;
+                    log::info!("Hot reloaded `{}`", data.id);
+                }
+                Err(e) => log::error!("Failed to hot reload, keeping the previous version: {}", e),
+            }
+        });
+        self.workers.invalidate();
     }
 
     pub fn stop(&mut self) {
         self.control.stop();
     }
 
-    async fn handle_msg(&mut self, evt: &messages::Privmsg<'_>, lua: &'lua mlua::Lua) {
-        if !evt.data.starts_with("xD") {
-            return;
+    /// Lets the bosses run their built-ins by whispering the bot, with or without a prefix.
+    async fn handle_whisper(&mut self, event: &Event, lua: &'lua mlua::Lua) {
+        let (user, message) = match (&event.user, &event.message) {
+            (Some(user), Some(message)) => (user.clone(), message.clone()),
+            _ => return,
+        };
+        let prefix = config::Prefix {
+            prefixes: self.config.prefixes.clone(),
+            mention: false,
+        };
+        let message = util::strip_command_prefix(&message, &prefix, None)
+            .unwrap_or_else(|| message.trim());
+        if let Some(responses) = self.handle_admin(&user, message, lua).await {
+            log::info!("{} whispered `{}`", user, message);
+            for response in responses {
+                self.whisper(&user, response).await;
+            }
         }
+    }
 
-        // hardcoded "xD" response because it needs to exist
-        if evt.data.trim() == "xD" {
-            self.send(&evt.channel, "xD").await;
-            return;
+    /// Handles the built-ins of the bosses, from a channel or a whisper.
+    /// Returns the responses, or `None` if the message isn't one of them.
+    async fn handle_admin(
+        &mut self,
+        user: &str,
+        message: &str,
+        lua: &'lua mlua::Lua,
+    ) -> Option<Vec<String>> {
+        if !self.is_boss(user) {
+            return None;
         }
 
-        if evt.data.trim() == "xD stop" && self.is_boss(&evt.name) {
+        if message == "stop" {
             self.stop();
-            return;
+            return Some(Vec::new());
         }
 
-        if evt.data.starts_with("xD reload all") && self.is_boss(&evt.name) {
+        if message.starts_with("reload all") {
             log::info!("Attempting to reload commands.json");
-            match load_commands(lua, "commands.json") {
-                Ok(commands) => {
-                    log::info!("Successfully reloaded commands.json");
-                    self.commands = commands;
-                    self.send(
-                        &evt.channel,
-                        format!("👉 Successfully reloaded the commands file."),
-                    )
-                    .await
-                }
-                Err(e) => {
-                    log::error!("Failed to reload commands.json: {}", e);
-                    self.send(&evt.channel, "WAYTOODANK ❗❗ something broke".to_owned())
-                        .await;
+            let (commands, report) = load_commands(lua, COMMANDS_FILE, Some(&self.commands));
+            report.log();
+            if report.is_ok() {
+                log::info!("Successfully reloaded commands.json");
+            }
+            self.commands = commands;
+            self.workers.invalidate();
+            self.refresh_command_list();
+            let hooks_report = self.reload_hooks(lua);
+            let timers_report = self.reload_timers(lua);
+            self.watch_scripts();
+            let mut responses = report.summary();
+            for report in &[hooks_report, timers_report] {
+                if !report.is_ok() {
+                    responses.extend(report.summary());
                 }
             }
-            return;
+            return Some(responses);
         }
 
-        if evt.data.starts_with("xD reload ") && self.is_boss(&evt.name) {
-            let _message = util::strip_prefix(&evt.data, "xD reload ");
-            match util::reload_command(&mut self.commands, _message, |cmd| {
-                util::load_file(&cmd.path)
-                    .and_then(|source| command::load_lua(&lua, _message, &source))
+        if message.starts_with("reload ") {
+            let _message = util::strip_prefix(message, "reload ");
+            let response = match util::reload_command(&mut self.commands, _message, |cmd| {
+                command::compile(&lua, &cmd.id, &cmd.path, &cmd.sandbox)
                     .map(|script| cmd.script = script)
-                    .map_err(|e| e.inner)
+                    .map_err(BoxedError::from)
             }) {
                 Ok(_) => {
-                    self.send(
-                        &evt.channel,
-                        format!("👉 Successfully reloaded `{}`", _message),
-                    )
-                    .await
+                    self.workers.invalidate();
+                    format!("👉 Successfully reloaded `{}`", _message)
                 }
                 Err(e) => {
                     log::error!("Failed to reload `{}`: {}", _message, e);
-                    self.send(&evt.channel, "WAYTOODANK ❗❗ something broke".to_owned())
-                        .await;
+                    match e.downcast_ref::<LoadError>() {
+                        Some(e) => format!(
+                            "WAYTOODANK ❗❗ failed to reload `{}` ({}), kept the previous version",
+                            _message, e.kind
+                        ),
+                        None => "WAYTOODANK ❗❗ something broke".to_owned(),
+                    }
                 }
+            };
+            return Some(vec![response]);
+        }
+
+        if message.starts_with("perm ") {
+            let response = self.edit_permissions(util::strip_prefix(message, "perm "));
+            return Some(vec![response]);
+        }
+
+        if message == "running" {
+            let running = self
+                .invocations
+                .list()
+                .into_iter()
+                .map(|invocation| {
+                    format!(
+                        "#{} {} in {} by {} ({}s)",
+                        invocation.id,
+                        invocation.command,
+                        invocation.channel,
+                        invocation.user,
+                        invocation.started.elapsed().as_secs()
+                    )
+                })
+                .collect::<Vec<_>>();
+            if running.is_empty() {
+                return Some(vec!["👉 nothing is running".to_owned()]);
             }
-            return;
+            return Some(util::pack_messages(
+                "👉 running: ",
+                &running,
+                ", ",
+                util::MAX_MESSAGE_LENGTH,
+            ));
         }
 
-        if evt.data.starts_with("xD help ") {
-            let name = util::strip_prefix(&evt.data, "xD help ");
-            log::info!("Help for command {}", name);
-            let (data, _) = match util::find_command(&self.commands, &name) {
-                Some(found) => found,
-                None => {
-                    return;
+        if message.starts_with("cancel ") {
+            let id = util::strip_prefix(message, "cancel ").trim();
+            let response = match id.trim_start_matches('#').parse::<u64>() {
+                Ok(id) => match self.invocations.cancel(id) {
+                    Some(invocation) => {
+                        log::info!(
+                            "{} cancelled `{}` (#{}) in {}",
+                            user,
+                            invocation.command,
+                            id,
+                            invocation.channel
+                        );
+                        format!("👉 cancelled #{} {}", id, invocation.command)
+                    }
+                    None => format!("FeelsDankMan #{} isn't running", id),
+                },
+                Err(_) => "FeelsDankMan usage: cancel <id>".to_owned(),
+            };
+            return Some(vec![response]);
+        }
+
+        if message.starts_with("join ") || message.starts_with("part ") {
+            let (action, channel) = message.split_at(5);
+            let channel = channel.trim();
+            let result = if action == "join " {
+                self.join(channel).await
+            } else {
+                self.part(channel).await
+            };
+            let response = match result {
+                Ok(true) if action == "join " => format!("👉 joined {}", channel),
+                Ok(true) => format!("👉 left {}", channel),
+                Ok(false) if action == "join " => format!("FeelsDankMan already in {}", channel),
+                Ok(false) => format!("FeelsDankMan not in {}", channel),
+                Err(e) => {
+                    log::error!("Failed to {}{}: {}", action, channel, e);
+                    format!("FeelsDankMan {}", e)
                 }
             };
-            self.send(&evt.channel, format!("FeelsDankMan 👉 {}", data.usage))
-                .await;
+            return Some(vec![response]);
+        }
+
+        if message == "channels" {
+            let channels = self.channels.lock().unwrap().list();
+            return Some(util::pack_messages(
+                "👉 channels: ",
+                &channels,
+                ", ",
+                util::MAX_MESSAGE_LENGTH,
+            ));
+        }
+
+        if message == "workers" {
+            return Some(vec![format!("👉 workers: {}", self.workers.stats())]);
+        }
+
+        if message == "timers" {
+            let now = chrono::Utc::now();
+            let timers = self
+                .scheduler
+                .list()
+                .into_iter()
+                .map(|task| {
+                    format!(
+                        "#{} {}{} ({}, next in {})",
+                        task.id,
+                        task.label,
+                        task.channel
+                            .map(|channel| format!(" in {}", channel))
+                            .unwrap_or_default(),
+                        task.schedule,
+                        util::duration_format(task.next - now)
+                    )
+                })
+                .collect::<Vec<_>>();
+            if timers.is_empty() {
+                return Some(vec!["👉 no timers".to_owned()]);
+            }
+            return Some(util::pack_messages(
+                "👉 timers: ",
+                &timers,
+                ", ",
+                util::MAX_MESSAGE_LENGTH,
+            ));
+        }
+
+        if message.starts_with("timers cancel ") {
+            let id = util::strip_prefix(message, "timers cancel ").trim();
+            let response = match id.trim_start_matches('#').parse::<u64>() {
+                Ok(id) => match self.scheduler.cancel(id) {
+                    Some(label) => {
+                        log::info!("{} cancelled the {} (#{})", user, label, id);
+                        format!("👉 cancelled #{} {}", id, label)
+                    }
+                    None => format!("FeelsDankMan there's no timer #{}", id),
+                },
+                Err(_) => "FeelsDankMan usage: timers cancel <id>".to_owned(),
+            };
+            return Some(vec![response]);
+        }
+
+        None
+    }
+
+    async fn handle_msg(&mut self, evt: &messages::Privmsg<'_>, lua: &'lua mlua::Lua) {
+        self.scheduler.record_message(&evt.channel);
+        let profile = self.profile(&evt.channel);
+        let message =
+            match util::strip_command_prefix(&evt.data, &profile.prefix, self.nickname.as_deref()) {
+                Some(message) => message,
+                None => return,
+            };
+
+        if message.is_empty() {
+            // hardcoded "xD" response because it needs to exist
+            if evt.data.trim() == "xD" {
+                self.send(&evt.channel, "xD").await;
+            }
+            return;
+        }
+
+        if let Some(responses) = self.handle_admin(&evt.name, message, lua).await {
+            for response in responses {
+                self.send(&evt.channel, response).await;
+            }
+            return;
+        }
+
+        if (message == "profile" || message.starts_with("profile "))
+            && self.is_staff(&evt.name, &profile)
+        {
+            let response = self.edit_profile(
+                &evt.name,
+                &evt.channel,
+                util::strip_prefix(message, "profile"),
+            );
+            self.send(&evt.channel, response).await;
+            return;
+        }
+
+        if message == "commands" {
+            let available = self
+                .visible_commands(evt, &profile, &self.commands)
+                .into_iter()
+                .map(|data| data.id.clone())
+                .collect::<Vec<_>>();
+            for response in util::pack_messages(
+                "FeelsDankMan 👉 commands: ",
+                &available,
+                ", ",
+                util::MAX_MESSAGE_LENGTH,
+            ) {
+                self.send(&evt.channel, response).await;
+            }
+            return;
+        }
+
+        if message == "help" {
+            self.send(
+                &evt.channel,
+                "FeelsDankMan 👉 help <command> shows the usage of a command, commands lists them",
+            )
+            .await;
+            return;
+        }
+
+        if message.starts_with("help ") {
+            let name = util::strip_prefix(message, "help ");
+            log::info!("Help for command {}", name);
+            let response = self.help(evt, &profile, name);
+            self.send(&evt.channel, response).await;
             return;
         }
 
-        let message = util::strip_prefix(&evt.data, "xD ");
         if let Some((command, args)) = util::find_command(&self.commands, message) {
+            if !self.can_run(evt, &profile, &command) {
+                log::info!("{} isn't allowed to run `{}`", evt.name, command.id);
+                return;
+            }
+
+            let raw_args = util::skip_words(
+                message,
+                message.split_whitespace().count() - args.as_ref().map_or(0, Vec::len),
+            );
+            let mut args = util::format_args(evt, &command, args);
+            if !self.is_staff(&evt.name, &profile) {
+                let cooldown = command.cooldown.scaled(profile.cooldown_multiplier);
+                if let Some(left) =
+                    self.cooldowns
+                        .remaining(&command.id, &cooldown, &evt.channel, &evt.name)
+                {
+                    log::info!(
+                        "Command `{}` is on cooldown for {} in {}",
+                        command.id,
+                        evt.name,
+                        evt.channel
+                    );
+                    if self.config.cooldown_reply
+                        && self
+                            .cooldowns
+                            .notify(&command.id, &evt.channel, &evt.name, left)
+                    {
+                        self.send(
+                            &evt.channel,
+                            format!(
+                                "FeelsDankMan ⏳ on cooldown, {}s left",
+                                left.as_secs() + (left.subsec_nanos() > 0) as u64
+                            ),
+                        )
+                        .await;
+                    }
+                    return;
+                }
+                self.cooldowns
+                    .trigger(&command.id, &cooldown, &evt.channel, &evt.name);
+            }
+
+            // Parsed after the cooldown, so that a malformed invocation counts as a use
+            // and the usage reply can't be spammed
+            if let Some(schema) = command.schema.as_ref() {
+                // The raw remainder, so that the whitespace inside quotes is kept
+                match args::tokenize(raw_args).and_then(|tokens| schema.parse(tokens)) {
+                    Ok(parsed) => args.parsed = Some(parsed),
+                    Err(e) => {
+                        self.send(
+                            &evt.channel,
+                            format!("FeelsDankMan {}. Usage: {}", e, schema.usage(&command.id)),
+                        )
+                        .await;
+                        return;
+                    }
+                }
+            }
+
+            let reply_to = match (command.reply_via_whisper, &args.message_id) {
+                (true, _) => Destination::Whisper(evt.name.to_string()),
+                (false, Some(parent)) if command.reply_in_thread => Destination::Thread {
+                    channel: evt.channel.to_string(),
+                    parent: parent.clone(),
+                },
+                _ => Destination::Channel(evt.channel.to_string()),
+            };
+
             if command.is_expensive {
-                let thread_name = format!(
-                    "cmd-{}@{{bot_uptime = {}}}",
-                    command.name,
-                    util::duration_format(chrono::Utc::now() - self.start),
-                );
-                log::info!(
-                    "Command `{}` is expensive, spawning a new thread named `{}`",
-                    command.name,
-                    thread_name,
-                );
-                let path = command.path.clone();
-                let local_lua = mlua::Lua::new();
-                let args = util::format_args(evt, args);
-                let channel = evt.channel.clone().into_owned();
-                let mut control = self.control.clone();
-                crate::lua::init_globals_for_lua(&local_lua, &self);
-
-                std::thread::Builder::new()
-                    .name(thread_name)
-                    .spawn(move || {
-                        let start = chrono::Utc::now();
-                        (|| {
-                            let fun = thread_try!(
-                                util::load_file(&path).and_then(|source| {
-                                    local_lua
-                                        .load(&source)
-                                        .into_function()
-                                        .map_err(|e| BackendError::from(format!("{}", e)))
-                                }),
-                                "Failed to compile the script: {:?}"
-                            );
-
-                            let mut rt = thread_try!(
-                                tokio::runtime::Runtime::new(),
-                                "Failed to create a new tokio runtime: {:?}"
-                            );
-                            rt.block_on(async move {
-                                let response = fun
-                                    .call_async::<mlua::Variadic<String>, Option<String>>(args)
-                                    .await;
-                                let response = match response {
-                                    Ok(Some(resp)) => resp,
-                                    Ok(None) => return,
-                                    Err(e) => {
-                                        thread_error!("Failed to execute script: {:?}", e);
-                                        send_in_thread(
-                                            &mut control,
-                                            &channel,
-                                            "WAYTOODANK devs broke something!",
-                                        )
-                                        .await;
-                                        return;
-                                    }
-                                };
-                                send_in_thread(&mut control, &channel, response).await;
-                            })
-                        })();
-                        thread_info!(
-                            "Terminating the task thread (thread lived for {})",
-                            util::duration_format(chrono::Utc::now() - start)
-                        );
-                    })
-                    .unwrap();
+                let job = workers::Job {
+                    id: command.id.clone(),
+                    path: command.path.clone(),
+                    sandbox: command.sandbox.clone(),
+                    instruction_limit: self.instruction_limit(&command.sandbox),
+                    timeout: self.timeout(command.timeout),
+                    reply_to,
+                    args,
+                    running: self.invocations.start(&command.id, &evt.channel, &evt.name),
+                };
+                if self.workers.submit(job).is_err() {
+                    log::warn!(
+                        "The worker queue is full, rejected `{}` ({})",
+                        command.id,
+                        self.workers.stats()
+                    );
+                    self.send(&evt.channel, "FeelsDankMan ⏳ too busy right now, try again later")
+                        .await;
+                }
                 return;
             }
-            let response = match command
-                .script
-                .clone()
-                .call_async::<mlua::Variadic<String>, Option<String>>(util::format_args(evt, args))
-                .await
+
+            // Runs on the main Lua state, polled by the event loop alongside the other commands
+            let future = run_script(
+                command.id.clone(),
+                command.script.clone(),
+                args,
+                self.invocations.start(&command.id, &evt.channel, &evt.name),
+                self.timeout(command.timeout),
+                self.budget.clone(),
+                self.instruction_limit(&command.sandbox),
+            );
+            if !self
+                .dispatch
+                .spawn(&evt.channel, reply_to, profile.concurrency.limit, future)
             {
-                Ok(Some(resp)) => resp,
-                Ok(None) => return,
-                Err(e) => {
-                    log::error!("Failed to execute script: {:?}", e);
-                    "WAYTOODANK devs broke something!".to_owned()
-                }
+                log::warn!(
+                    "Too many commands waiting in {}, dropped `{}`",
+                    evt.channel,
+                    command.id
+                );
+            }
+        }
+    }
+
+    /// Reloads the hooks file, logging the errors. Broken hooks keep their previous versions.
+    fn reload_hooks(&mut self, lua: &'lua mlua::Lua) -> LoadReport {
+        let (hooks, report) = Hooks::load(lua, HOOKS_FILE, Some(&self.hooks));
+        report.log();
+        self.hooks = hooks;
+        report
+    }
+
+    /// Runs the hooks of the event alongside the commands of its channel.
+    fn run_hooks(&mut self, event: Event) {
+        for hook in self.hooks.matching(&event) {
+            // Whispers have no channel, their hooks reply privately
+            let to = match (&event.channel, &event.user) {
+                (Some(channel), _) => Destination::Channel(channel.clone()),
+                (None, Some(user)) => Destination::Whisper(user.clone()),
+                (None, None) => continue,
             };
-            self.send(&evt.channel.clone(), response).await;
+            let channel = to.key().to_owned();
+            let user = event.user.as_deref().unwrap_or_default();
+            // Nobody in the chat asked for the hook, so its errors are only logged
+            let future = execute_script(
+                hook.id.clone(),
+                hook.script,
+                event.clone(),
+                self.invocations.start(&hook.id, &channel, user),
+                self.timeout(hook.timeout),
+                self.budget.clone(),
+                self.instruction_limit(&hook.sandbox),
+            )
+            .map(Result::unwrap_or_default);
+            let limit = self.profile(&channel).concurrency.limit;
+            if !self.dispatch.spawn(&channel, to, limit, future) {
+                log::warn!("Too many commands waiting in {}, dropped the {}", channel, hook.id);
+            }
         }
     }
 
-    pub async fn join(&mut self, channel: &str, nickname: &str) {
-        log::info!("Connected to {} as {}", &channel, nickname);
-        self.control
-            .writer()
-            .join(channel)
-            .await
-            .unwrap_or_else(|e| {
-                log::error!(
-                    "Caught a critical error while joining a channel {}: {:?}",
-                    channel,
-                    e
-                );
+    /// Reloads the timers file, logging the errors, and restarts the declared timers.
+    /// The functions scheduled by the scripts keep running.
+    fn reload_timers(&mut self, lua: &'lua mlua::Lua) -> LoadReport {
+        let (timers, declared, report) = timers::load_timers(lua, TIMERS_FILE, Some(&self.timers));
+        report.log();
+        if let Some(declared) = declared {
+            self.scheduler.set_declared(declared);
+        }
+        self.timers = timers;
+        report
+    }
+
+    /// Runs the timers and the scheduled functions that are due.
+    fn run_timers(&mut self, lua: &'lua mlua::Lua) {
+        for due in self.scheduler.take_due(chrono::Utc::now()) {
+            let channel = due.channel.clone().unwrap_or_default();
+            match due.run {
+                DueAction::Script {
+                    name,
+                    live_only,
+                    messages,
+                    count,
+                } => {
+                    let timer = match self.timers.get(&name) {
+                        Some(timer) => timer.clone(),
+                        None => continue,
+                    };
+                    let tick = timers::Tick {
+                        channel: channel.clone(),
+                        timer: name,
+                        messages,
+                    };
+                    let script = run_script(
+                        timer.id.clone(),
+                        timer.script,
+                        tick,
+                        self.invocations.start(&timer.id, &channel, ""),
+                        self.timeout(timer.timeout),
+                        self.budget.clone(),
+                        self.instruction_limit(&timer.sandbox),
+                    );
+                    let (live, scheduler) = (self.live.clone(), self.scheduler.clone());
+                    let (id, live_channel) = (timer.id, channel.clone());
+                    let task = due.id;
+                    let future = async move {
+                        if live_only {
+                            if !live.is_live(&live_channel).await {
+                                log::debug!("Skipped the {}, {} is offline", id, live_channel);
+                                return None;
+                            }
+                            scheduler.mark_seen(task, count);
+                        }
+                        script.await
+                    };
+                    self.spawn_timer(&due.label, &channel, future);
+                }
+                DueAction::Callback(key) => {
+                    let function = match lua.registry_value::<mlua::Function>(&key) {
+                        Ok(function) => function,
+                        Err(e) => {
+                            log::error!("Failed to run the {} (#{}): {}", due.label, due.id, e);
+                            continue;
+                        }
+                    };
+                    let future = run_script(
+                        format!("{} #{}", due.label, due.id),
+                        function,
+                        (),
+                        self.invocations.start(&due.label, &channel, ""),
+                        self.timeout(None),
+                        self.budget.clone(),
+                        self.config.sandbox.instruction_limit,
+                    );
+                    // Without a channel the result goes nowhere
+                    let has_channel = due.channel.is_some();
+                    let future = async move { future.await.filter(|_| has_channel) };
+                    self.spawn_timer(&due.label, &channel, future);
+                }
+            }
+        }
+        // Frees the functions of the tasks that are done
+        lua.expire_registry_values();
+    }
+
+    fn spawn_timer<F>(&mut self, label: &str, channel: &str, future: F)
+    where
+        F: std::future::Future<Output = Option<String>> + 'lua,
+    {
+        let limit = self.profile(channel).concurrency.limit;
+        if !self
+            .dispatch
+            .spawn(channel, Destination::Channel(channel.to_owned()), limit, future)
+        {
+            log::warn!(
+                "Too many commands waiting in {}, dropped the {}",
+                channel,
+                label
+            );
+        }
+    }
+
+    fn instruction_limit(&self, sandbox: &Sandbox) -> u64 {
+        sandbox
+            .instruction_limit
+            .unwrap_or(self.config.sandbox.instruction_limit)
+    }
+
+    fn timeout(&self, timeout: Option<u64>) -> Option<std::time::Duration> {
+        match timeout.unwrap_or(self.config.command_timeout) {
+            0 => None,
+            secs => Some(std::time::Duration::from_secs(secs)),
+        }
+    }
+
+    /// Flattens the command tree into the commands the caller may run.
+    fn visible_commands<'c>(
+        &self,
+        evt: &messages::Privmsg<'_>,
+        profile: &Profile,
+        commands: &'c HashMap<String, Command<'lua>>,
+    ) -> Vec<&'c CommandData<'lua>> {
+        util::flatten_commands(commands)
+            .into_iter()
+            .filter(|data| self.can_run(evt, profile, data))
+            .collect()
+    }
+
+    /// Renders the help for the command, the subcommands of a group, or suggestions on a typo.
+    fn help(&self, evt: &messages::Privmsg<'_>, profile: &Profile, name: &str) -> String {
+        let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        match util::find_node(&self.commands, &name) {
+            Some(Command {
+                data: Some(data), ..
+            }) if self.can_run(evt, profile, data) => {
+                return format!("FeelsDankMan 👉 {}", help::synopsis(data));
+            }
+            Some(Command {
+                data: None,
+                commands: Some(commands),
+                ..
+            }) => {
+                let subcommands = self
+                    .visible_commands(evt, profile, commands)
+                    .into_iter()
+                    .map(|data| data.id.clone())
+                    .collect::<Vec<_>>();
+                if !subcommands.is_empty() {
+                    return format!(
+                        "FeelsDankMan 👉 {} subcommands: {}",
+                        name,
+                        subcommands.join(", ")
+                    );
+                }
+            }
+            _ => (),
+        }
+
+        let candidates = self
+            .visible_commands(evt, profile, &self.commands)
+            .into_iter()
+            .flat_map(|data| {
+                let parent = &data.id[..data.id.len() - data.name.len()];
+                std::iter::once(data.id.clone())
+                    .chain(data.aliases.iter().map(move |alias| format!("{}{}", parent, alias)))
             })
+            .collect::<Vec<_>>();
+        let suggestions = help::suggest(&name, candidates.iter().map(String::as_str));
+        if suggestions.is_empty() {
+            format!("FeelsDankMan command `{}` not found", name)
+        } else {
+            format!(
+                "FeelsDankMan command `{}` not found. Did you mean: {}?",
+                name,
+                suggestions.join(", ")
+            )
+        }
+    }
+
+    /// Handles `profile`, `profile set <setting> <value>` and `profile reset <setting>`
+    /// for the current channel.
+    fn edit_profile(&mut self, user: &str, channel: &str, args: &str) -> String {
+        let tokens = args.split_whitespace().collect::<Vec<_>>();
+        let result = match tokens.as_slice() {
+            [] => return describe_profile(&self.profile(channel)),
+            // The channel's staff can't appoint more staff
+            ["set", "staff", ..] | ["reset", "staff"] if !self.is_boss(user) => {
+                return "FeelsDankMan only the bot admins can edit the staff".to_owned()
+            }
+            ["set", key, value @ ..] if !value.is_empty() => {
+                let value = value.join(" ");
+                self.profiles
+                    .write()
+                    .unwrap()
+                    .set(channel, key, Some(&value))
+            }
+            ["reset", key] => self.profiles.write().unwrap().set(channel, key, None),
+            _ => {
+                return "FeelsDankMan usage: profile [set <setting> <value> | reset <setting>]"
+                    .to_owned()
+            }
+        };
+        match result {
+            Ok(()) => format!("👉 updated the {} profile", channel),
+            Err(e) => {
+                log::error!("Failed to update the profile of {}: {}", channel, e);
+                format!("FeelsDankMan {}", e)
+            }
+        }
+    }
+
+    /// Handles `perm grant|deny|reset <user> <command>`.
+    fn edit_permissions(&mut self, args: &str) -> String {
+        let tokens = args.split_whitespace().collect::<Vec<_>>();
+        if tokens.len() < 3 {
+            return "FeelsDankMan usage: perm grant|deny|reset <user> <command>".to_owned();
+        }
+        let value = match tokens[0] {
+            "grant" => Some(Override::Grant),
+            "deny" => Some(Override::Deny),
+            "reset" => None,
+            other => return format!("FeelsDankMan unknown action `{}`", other),
+        };
+        let user = tokens[1];
+        let command = tokens[2..].join(" ");
+        // Overrides are keyed by the canonical path, so resolve the aliases first
+        let command = match util::find_node(&self.commands, &command) {
+            Some(Command {
+                data: Some(data), ..
+            }) => data.id.clone(),
+            Some(_) => command,
+            None => return format!("FeelsDankMan command `{}` doesn't exist", command),
+        };
+        match self.permissions.write().unwrap().set(user, &command, value) {
+            Ok(()) => format!("👉 {} `{}` for {}", tokens[0], command, user),
+            Err(e) => {
+                log::error!("Failed to update the permissions: {}", e);
+                "WAYTOODANK ❗❗ something broke".to_owned()
+            }
+        }
+    }
+
+    /// Joins the channel and adds it to the persisted channel list.
+    pub async fn join(&mut self, channel: &str) -> Result<bool, BackendError> {
+        channels::join(&mut self.control, &self.channels, channel).await
+    }
+
+    /// Leaves the channel and removes it from the persisted channel list.
+    pub async fn part(&mut self, channel: &str) -> Result<bool, BackendError> {
+        channels::part(&mut self.control, &self.channels, channel).await
     }
 
     async fn join_configured_channels(&mut self, nickname: &str) {
-        for channel in self.config.channels.iter() {
+        let channels = self.channels.lock().unwrap().list();
+        for channel in channels {
             log::info!("Connected to {} as {}", &channel, nickname);
             self.control
                 .writer()
-                .join(channel)
+                .join(&channel)
                 .await
                 .unwrap_or_else(|e| {
                     log::error!(
@@ -307,32 +1167,175 @@ impl<'lua> Bot<'lua> {
         }
     }
 
+    /// Queues a message to the channel, see `outbox` for when it gets sent.
     async fn send<S: Into<String>>(&mut self, channel: &str, message: S) {
-        send(&mut self.control, channel, message)
+        self.outbox.send(channel, message).unwrap_or_else(|e| {
+            log::error!(
+                "Caught a critical error while sending a response to the channel {}: {}",
+                channel,
+                e
+            );
+        })
+    }
+
+    /// Queues a whisper to the user, see `outbox` for when it gets sent.
+    async fn whisper<S: Into<String>>(&mut self, user: &str, message: S) {
+        self.deliver(&Destination::Whisper(user.to_owned()), message)
             .await
-            .unwrap_or_else(|e| {
-                log::error!(
-                    "Caught a critical error while sending a response to the channel {}: {:?}",
-                    channel,
-                    e
-                );
-            })
+    }
+
+    async fn deliver<S: Into<String>>(&mut self, to: &Destination, message: S) {
+        self.outbox.deliver(to, message).unwrap_or_else(|e| {
+            log::error!(
+                "Caught a critical error while sending a response to {:?}: {}",
+                to,
+                e
+            );
+        })
     }
 }
 
-async fn send<S: Into<String>>(
-    control: &mut Control,
-    channel: &str,
-    message: S,
-) -> Result<(), twitchchat::Error> {
-    control.writer().privmsg(channel, message.into()).await
+/// Checks whether the user is a global staff member or one of the channel's own.
+fn is_staff(bosses: &HashSet<String>, name: &str, profile: &Profile) -> bool {
+    bosses.contains(name) || profile.staff.contains(&name.to_lowercase())
+}
+
+/// Checks whether the command is enabled in the channel, its permission level
+/// and the caller's overrides.
+fn allowed(
+    permissions: &Permissions,
+    bosses: &HashSet<String>,
+    profile: &Profile,
+    user: &str,
+    roles: Roles,
+    command: &str,
+    required: Permission,
+) -> bool {
+    if !profile.is_enabled(command) {
+        return false;
+    }
+    // Only the bosses bypass the overrides, a channel's staff ranks as its broadcaster
+    let mut level = roles.level(bosses.contains(user));
+    if profile.staff.contains(&user.to_lowercase()) {
+        level = level.max(Permission::Broadcaster);
+    }
+    permissions.allows(user, command, required, level)
 }
 
-async fn send_in_thread<S: Into<String>>(control: &mut Control, channel: &str, message: S) {
-    send(control, channel, message).await.unwrap_or_else(|e| {
+/// Summarizes a channel's profile in a chat message.
+fn describe_profile(profile: &Profile) -> String {
+    let mut parts = vec![
+        format!("prefixes: {}", profile.prefix.prefixes.join(" ")),
+        format!("language: {}", profile.language),
+        format!("cooldowns: ×{}", profile.cooldown_multiplier),
+        format!(
+            "greeting: {}",
+            profile.greeting.as_deref().unwrap_or("none")
+        ),
+    ];
+    if let Some(enabled) = &profile.enabled {
+        parts.push(format!("enabled: {}", enabled.join(", ")));
+    }
+    if !profile.disabled.is_empty() {
+        parts.push(format!("disabled: {}", profile.disabled.join(", ")));
+    }
+    if let Some(id) = &profile.streamelements_id {
+        parts.push(format!("StreamElements: {}", id));
+    }
+    if !profile.staff.is_empty() {
+        let mut staff = profile.staff.iter().cloned().collect::<Vec<_>>();
+        staff.sort();
+        parts.push(format!("staff: {}", staff.join(", ")));
+    }
+    format!("👉 {}", parts.join(" | "))
+}
+
+/// Runs a script on the main Lua state within its limits, turning a failure into the response.
+async fn run_script<'lua, A: mlua::ToLuaMulti<'lua>>(
+    id: String,
+    script: mlua::Function<'lua>,
+    args: A,
+    running: Running,
+    timeout: Option<std::time::Duration>,
+    budget: InstructionBudget,
+    instruction_limit: u64,
+) -> Option<String> {
+    execute_script(
+        id,
+        script,
+        args,
+        running,
+        timeout,
+        budget,
+        instruction_limit,
+    )
+    .await
+    .unwrap_or_else(|e| Some(error_response(&e)))
+}
+
+/// Runs a script like `run_script`, logging a failure and returning it instead of the response.
+async fn execute_script<'lua, A: mlua::ToLuaMulti<'lua>>(
+    id: String,
+    script: mlua::Function<'lua>,
+    args: A,
+    running: Running,
+    timeout: Option<std::time::Duration>,
+    budget: InstructionBudget,
+    instruction_limit: u64,
+) -> Result<Option<String>, mlua::Error> {
+    let response = running
+        .run(
+            timeout,
+            budget.run(instruction_limit, script.call_async::<A, Option<String>>(args)),
+        )
+        .await;
+    match response {
+        Ok(Ok(resp)) => Ok(resp),
+        Ok(Err(e)) => {
+            log::error!("Failed to execute `{}`: {:?}", id, e);
+            Err(e)
+        }
+        Err(interrupted) => {
+            log::info!("`{}` was interrupted: {:?}", id, interrupted);
+            Ok(interrupted_response(&id, interrupted))
+        }
+    }
+}
+
+/// The chat response to a failed script.
+fn error_response(error: &mlua::Error) -> String {
+    match sandbox::describe_error(error) {
+        Some(reason) => format!("WAYTOODANK ❗ {}", reason),
+        None => "WAYTOODANK devs broke something!".to_owned(),
+    }
+}
+
+/// The chat response to an interrupted script. Cancelled scripts stay quiet,
+/// the staff member who cancelled it already got a confirmation.
+fn interrupted_response(command: &str, interrupted: Interrupted) -> Option<String> {
+    match interrupted {
+        Interrupted::TimedOut(timeout) => Some(format!(
+            "WAYTOODANK ⏱ `{}` timed out after {}s",
+            command,
+            timeout.as_secs()
+        )),
+        Interrupted::Cancelled => None,
+    }
+}
+
+/// Waits for the next script change, or forever if hot reloading is disabled.
+async fn next_change(watcher: &mut Option<ScriptWatcher>) -> Option<String> {
+    match watcher {
+        Some(watcher) => watcher.changed().await,
+        None => futures::future::pending().await,
+    }
+}
+
+fn send_in_thread<S: Into<String>>(outbox: &Outbox, to: &Destination, message: S) {
+    outbox.deliver(to, message).unwrap_or_else(|e| {
         thread_error!(
-            "Caught a critical error while sending a response to the channel {}: {:?}",
-            channel,
+            "Caught a critical error while sending a response to {:?}: {}",
+            to,
             e
         );
     })
@@ -352,6 +1355,39 @@ pub fn init_api_globals(lua: &mlua::Lua, api: APIStorage, bot: BotInfo) {
 pub struct BotInfo {
     pub start: chrono::DateTime<chrono::Utc>,
     control: Control,
+    outbox: Outbox,
+    channels: SharedChannels,
+    profiles: SharedProfiles,
+    permissions: SharedPermissions,
+    bosses: Arc<HashSet<String>>,
+    commands: CommandList,
+    scheduler: Option<Scheduler>,
+    /// The command whose environment this is, owning the functions it schedules.
+    owner: String,
+    /// Whether `bot:join` and `bot:part` are allowed, see `sandbox::command_env`.
+    manage_channels: bool,
+}
+
+impl BotInfo {
+    /// The `bot` of a command's environment.
+    pub fn for_command(&self, owner: &str, manage_channels: bool) -> BotInfo {
+        BotInfo {
+            owner: owner.to_owned(),
+            manage_channels,
+            ..self.clone()
+        }
+    }
+
+    fn check_manage_channels(&self) -> Result<(), BackendError> {
+        if self.manage_channels {
+            Ok(())
+        } else {
+            Err(BackendError::from(
+                "Joining and leaving channels needs \"channels\" in the sandbox allow list"
+                    .to_owned(),
+            ))
+        }
+    }
 }
 
 impl UserData for BotInfo {
@@ -359,11 +1395,155 @@ impl UserData for BotInfo {
         methods.add_method("uptime", |_, instance, ()| {
             Ok(util::duration_format(chrono::Utc::now() - instance.start))
         });
-        methods.add_async_method(
-            "send",
-            |lua, mut instance, (chan, msg): (String, String)| async move {
-                let res = instance.control.writer().privmsg(&chan, msg).await;
-                Ok(match res {
+        // Lists the commands the caller of `ctx` can run, or the ones up to a permission level.
+        methods.add_method("commands", |lua, instance, caller: mlua::Value| {
+            let entries = instance.commands.read().unwrap();
+            let entries = match caller {
+                mlua::Value::Table(ctx) => {
+                    let Caller {
+                        channel,
+                        user,
+                        roles,
+                    } = context::caller(&ctx).ok_or_else(|| {
+                        mlua::Error::RuntimeError("Expected the `ctx` table".to_owned())
+                    })?;
+                    let profile = instance.profiles.read().unwrap().get(&channel);
+                    let permissions = instance.permissions.read().unwrap();
+                    entries
+                        .iter()
+                        .filter(|entry| {
+                            allowed(
+                                &permissions,
+                                &instance.bosses,
+                                &profile,
+                                &user,
+                                roles,
+                                &entry.id,
+                                entry.permission,
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                }
+                mlua::Value::String(level) => {
+                    let level = level
+                        .to_str()?
+                        .parse::<Permission>()
+                        .map_err(|e| mlua::Error::RuntimeError(e.to_string()))?;
+                    entries
+                        .iter()
+                        .filter(|entry| entry.permission <= level)
+                        .collect()
+                }
+                _ => entries.iter().collect(),
+            };
+            let entries = entries
+                .into_iter()
+                .map(|entry| {
+                    let table = lua.create_table()?;
+                    table.set("name", entry.id.clone())?;
+                    table.set("synopsis", entry.synopsis.clone())?;
+                    table.set("usage", entry.usage.clone())?;
+                    table.set("description", entry.description.clone())?;
+                    table.set("permission", entry.permission.to_string())?;
+                    Ok(table)
+                })
+                .collect::<mlua::Result<Vec<_>>>()?;
+            lua.create_sequence_from(entries)
+        });
+        methods.add_method("profile", |lua, instance, channel: String| {
+            let profile = instance.profiles.read().unwrap().get(&channel);
+            let table = lua.create_table()?;
+            table.set("prefixes", profile.prefix.prefixes)?;
+            table.set("language", profile.language)?;
+            table.set("cooldown_multiplier", profile.cooldown_multiplier)?;
+            table.set("greeting", profile.greeting)?;
+            table.set("streamelements_id", profile.streamelements_id)?;
+            table.set("staff", profile.staff.into_iter().collect::<Vec<_>>())?;
+            Ok(table)
+        });
+        // Runs the function once after a delay in milliseconds, or repeatedly on a cron expression.
+        // If it returns a string, it's sent to the channel, when given. See `timers` for the limits.
+        methods.add_method(
+            "schedule",
+            |lua,
+             instance,
+             (when, function, channel): (mlua::Value, mlua::Function, Option<String>)| {
+                let scheduler = match &instance.scheduler {
+                    Some(scheduler) => scheduler,
+                    None => {
+                        return Ok((
+                            mlua::Value::Nil,
+                            "Functions can't be scheduled from expensive commands".to_lua(lua)?,
+                        ))
+                    }
+                };
+                let schedule = match when {
+                    mlua::Value::Integer(ms) if ms >= 0 => {
+                        Ok(Schedule::After(std::time::Duration::from_millis(ms as u64)))
+                    }
+                    mlua::Value::Number(ms) if ms >= 0.0 => {
+                        Ok(Schedule::After(std::time::Duration::from_millis(ms as u64)))
+                    }
+                    mlua::Value::String(cron) => cron
+                        .to_str()
+                        .map_err(|e| BackendError::from(e.to_string()))
+                        .and_then(|cron| cron.parse())
+                        .map(Schedule::Cron),
+                    _ => Err(BackendError::from(
+                        "Expected a delay in milliseconds or a cron expression".to_owned(),
+                    )),
+                };
+                let channel = channel.map(|channel| format!("#{}", config::channel_key(&channel)));
+                let scheduled = schedule.and_then(|schedule| {
+                    let function = lua
+                        .create_registry_value(function)
+                        .map_err(|e| BackendError::from(e.to_string()))?;
+                    scheduler.add_callback(&instance.owner, channel, schedule, function)
+                });
+                Ok(match scheduled {
+                    Ok(id) => (id.to_lua(lua)?, mlua::Value::Nil),
+                    Err(e) => (mlua::Value::Nil, e.to_string().to_lua(lua)?),
+                })
+            },
+        );
+        // Only cancels the functions scheduled by the same command
+        methods.add_method("unschedule", |_, instance, id: u64| {
+            Ok(instance
+                .scheduler
+                .as_ref()
+                .and_then(|scheduler| scheduler.cancel_owned(id, &instance.owner))
+                .is_some())
+        });
+        methods.add_method("channels", |_, instance, ()| {
+            Ok(instance.channels.lock().unwrap().list())
+        });
+        methods.add_async_method("join", |lua, mut instance, channel: String| async move {
+            if let Err(e) = instance.check_manage_channels() {
+                return channel_result(lua, Err(e));
+            }
+            let result = channels::join(&mut instance.control, &instance.channels, &channel).await;
+            channel_result(lua, result)
+        });
+        methods.add_async_method("part", |lua, mut instance, channel: String| async move {
+            if let Err(e) = instance.check_manage_channels() {
+                return channel_result(lua, Err(e));
+            }
+            let result = channels::part(&mut instance.control, &instance.channels, &channel).await;
+            channel_result(lua, result)
+        });
+        methods.add_method("send", |lua, instance, (chan, msg): (String, String)| {
+            Ok(match instance.outbox.send(&chan, msg) {
+                Ok(()) => (mlua::Value::Boolean(true), mlua::Value::Nil),
+                Err(e) => (
+                    mlua::Value::Nil,
+                    mlua::Value::String(lua.create_string(&e.to_string())?),
+                ),
+            })
+        });
+        methods.add_method(
+            "reply",
+            |lua, instance, (chan, parent, msg): (String, String, String)| {
+                Ok(match instance.outbox.reply(&chan, &parent, msg) {
                     Ok(()) => (mlua::Value::Boolean(true), mlua::Value::Nil),
                     Err(e) => (
                         mlua::Value::Nil,
@@ -372,25 +1552,71 @@ impl UserData for BotInfo {
                 })
             },
         );
+        methods.add_method("whisper", |lua, instance, (user, msg): (String, String)| {
+            Ok(match instance.outbox.whisper(&user, msg) {
+                Ok(()) => (mlua::Value::Boolean(true), mlua::Value::Nil),
+                Err(e) => (
+                    mlua::Value::Nil,
+                    mlua::Value::String(lua.create_string(&e.to_string())?),
+                ),
+            })
+        });
     }
 }
 
+/// Converts the result of a join or a part to the `(ok, err)` pair returned to the scripts.
+fn channel_result(
+    lua: &mlua::Lua,
+    result: Result<bool, BackendError>,
+) -> mlua::Result<(mlua::Value, mlua::Value)> {
+    Ok(match result {
+        Ok(changed) => (mlua::Value::Boolean(changed), mlua::Value::Nil),
+        Err(e) => (
+            mlua::Value::Nil,
+            mlua::Value::String(lua.create_string(&e.to_string())?),
+        ),
+    })
+}
+
+#[derive(Clone)]
 pub struct APIStorage {
     pub streamelements: Option<ConsumerStreamElementsAPI>,
     pub youtube_playlist: Option<ConsumerYouTubePlaylistAPI>,
+    /// Maps the channels to their `streamelements_id`.
+    pub profiles: SharedProfiles,
 }
 
 impl UserData for APIStorage {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("streamelements", |lua, instance, ()| {
-            Ok(match instance.streamelements.clone() {
-                Some(api) => (api.to_lua(lua)?, mlua::Value::Nil),
-                None => (
-                    mlua::Value::Nil,
-                    mlua::Value::String(lua.create_string("StreamElements API is unavailable!")?),
-                ),
-            })
-        });
+        // With a channel, the API acts on the channel's `streamelements_id` if it has one
+        methods.add_method(
+            "streamelements",
+            |lua, instance, channel: Option<String>| {
+                let channel_id = channel.and_then(|channel| {
+                    instance
+                        .profiles
+                        .read()
+                        .unwrap()
+                        .get(&channel)
+                        .streamelements_id
+                });
+                Ok(match instance.streamelements.clone() {
+                    Some(api) => {
+                        let api = match channel_id {
+                            Some(id) => api.for_channel(id),
+                            None => api,
+                        };
+                        (api.to_lua(lua)?, mlua::Value::Nil)
+                    }
+                    None => (
+                        mlua::Value::Nil,
+                        mlua::Value::String(
+                            lua.create_string("StreamElements API is unavailable!")?,
+                        ),
+                    ),
+                })
+            },
+        );
         methods.add_method("youtube_playlist", |lua, instance, ()| {
             Ok(match instance.youtube_playlist.clone() {
                 Some(api) => (api.to_lua(lua)?, mlua::Value::Nil),
@@ -0,0 +1,463 @@
+//! Queues the outgoing messages and sends them within Twitch's rate limits.
+//!
+//! The bot can send 100 messages per 30 seconds to the channels where it's a moderator or VIP,
+//! and 20 elsewhere, where it also keeps to the channel's slow mode and to the 1 second bot
+//! slow mode of https://supinic.com/bot/channel-bots/levels. Whether the bot is privileged
+//! is learned from the USERSTATE of each channel, and the slow mode from its ROOMSTATE.
+//!
+//! Twitch drops a message identical to the previous one, so unless disabled for the channel,
+//! a repeated message gets an invisible marker appended, alternating with the plain one.
+//! Responses over the 500 character limit are split into several messages.
+//!
+//! Whispers go through the Helix API, since Twitch no longer takes them over chat, so they
+//! need the Helix credentials. They have a queue of their own, sent at most once per second
+//! to stay under Helix's whisper limits, and don't count towards the chat's rate limits.
+//! Replies are threaded under their parent message with the `reply-parent-msg-id` tag.
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use twitchchat::Control;
+
+use super::channels;
+use super::config::{channel_key, BotConfig};
+use super::helix::Helix;
+use super::util;
+use crate::BackendError;
+
+/// The window of Twitch's message rate limits.
+const RATE_WINDOW: Duration = Duration::from_secs(30);
+/// The messages per window when the bot is a regular user.
+const USER_RATE: usize = 20;
+/// The messages per window when the bot is a moderator or VIP.
+const PRIVILEGED_RATE: usize = 100;
+/// The slow mode the bot keeps to in the channels where it isn't a moderator or VIP.
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+/// The key of the whisper queue, which can't clash with a channel.
+const WHISPERS: &str = "(whispers)";
+
+/// Where a response goes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    /// The channel, with the `#`.
+    Channel(String),
+    /// The channel, as a reply to the message with the given id.
+    Thread { channel: String, parent: String },
+    /// The user, as a whisper.
+    Whisper(String),
+}
+
+impl Destination {
+    /// The channel, or the user for whispers.
+    pub fn key(&self) -> &str {
+        match self {
+            Destination::Channel(channel) | Destination::Thread { channel, .. } => channel,
+            Destination::Whisper(user) => user,
+        }
+    }
+}
+
+enum Outgoing {
+    Message {
+        channel: String,
+        text: String,
+        /// The id of the message replied to.
+        parent: Option<String>,
+        /// The user, for a whisper.
+        recipient: Option<String>,
+    },
+    Privileged { channel: String, privileged: bool },
+    SlowMode { channel: String, secs: u64 },
+}
+
+/// The sending end of the outgoing message queue, shared by the bot, its workers and the scripts.
+#[derive(Clone)]
+pub struct Outbox {
+    tx: mpsc::UnboundedSender<Outgoing>,
+    continuation: String,
+    max_parts: usize,
+    can_whisper: bool,
+}
+
+impl Outbox {
+    /// Spawns the task that sends the queued messages on the current runtime.
+    pub fn start(control: Control, config: &BotConfig, helix: Option<Helix>) -> Outbox {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let can_whisper = helix.is_some();
+        let task = OutboxTask {
+            control,
+            helix,
+            stale_after: Duration::from_secs(config.outbox.stale_after),
+            max_queue: config.outbox.max_queue,
+            r9k_bypass: config.r9k_bypass,
+            r9k_channels: config
+                .channel
+                .iter()
+                .filter_map(|(name, channel)| Some((name.clone(), channel.r9k_bypass?)))
+                .collect(),
+            r9k_marker: config.r9k_marker.clone(),
+            sent: VecDeque::new(),
+            channels: HashMap::new(),
+        };
+        tokio::spawn(task.run(rx));
+        Outbox {
+            tx,
+            continuation: config.outbox.continuation.clone(),
+            max_parts: config.outbox.max_parts.max(1),
+            can_whisper,
+        }
+    }
+
+    /// Queues a message to the channel, split into several if it's too long.
+    pub fn send<S: Into<String>>(&self, channel: &str, message: S) -> Result<(), BackendError> {
+        self.enqueue(channel, None, None, &message.into())
+    }
+
+    /// Queues a reply to the message with the given id. Only the first part is threaded.
+    pub fn reply<S: Into<String>>(
+        &self,
+        channel: &str,
+        parent: &str,
+        message: S,
+    ) -> Result<(), BackendError> {
+        // Both end up in a raw line, where they could smuggle in other commands
+        let channel = format!("#{}", channels::validate(channel)?);
+        let is_id = |c: char| matches!(c, '0'..='9' | 'a'..='f' | '-');
+        if parent.is_empty() || !parent.chars().all(is_id) {
+            return Err(BackendError::from(format!(
+                "`{}` isn't a valid message id",
+                parent
+            )));
+        }
+        self.enqueue(&channel, Some(parent), None, &message.into())
+    }
+
+    /// Queues a whisper to the user, split into several if it's too long.
+    pub fn whisper<S: Into<String>>(&self, user: &str, message: S) -> Result<(), BackendError> {
+        if !self.can_whisper {
+            return Err(BackendError::from(
+                "Whispers need `twitch_client_id` in secrets.toml".to_owned(),
+            ));
+        }
+        let user = user.trim_start_matches('@').to_lowercase();
+        if user.is_empty() || !user.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(BackendError::from(format!("`{}` isn't a valid user", user)));
+        }
+        self.enqueue(WHISPERS, None, Some(&user), &message.into())
+    }
+
+    pub fn deliver<S: Into<String>>(&self, to: &Destination, message: S) -> Result<(), BackendError> {
+        match to {
+            Destination::Channel(channel) => self.send(channel, message),
+            Destination::Thread { channel, parent } => self.reply(channel, parent, message),
+            Destination::Whisper(user) => self.whisper(user, message),
+        }
+    }
+
+    /// Splits the message to fit the length limit, and queues the parts.
+    fn enqueue(
+        &self,
+        channel: &str,
+        parent: Option<&str>,
+        recipient: Option<&str>,
+        message: &str,
+    ) -> Result<(), BackendError> {
+        // A line break would end the IRC line and start another command
+        let message = message.replace(|c| c == '\r' || c == '\n', " ");
+        let mut parts = util::split_message(&message, util::MAX_MESSAGE_LENGTH, &self.continuation);
+        if parts.len() > self.max_parts {
+            log::warn!(
+                "Dropped {} parts of a response to {}",
+                parts.len() - self.max_parts,
+                recipient.unwrap_or(channel)
+            );
+            parts.truncate(self.max_parts);
+        }
+        for (i, text) in parts.into_iter().enumerate() {
+            self.push(Outgoing::Message {
+                channel: channel.to_owned(),
+                text,
+                parent: parent.filter(|_| i == 0).map(str::to_owned),
+                recipient: recipient.map(str::to_owned),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Updates the bot's status in the channel, from the badges of its USERSTATE.
+    pub fn set_badges(&self, channel: &str, badges: &str) {
+        let privileged = badges.split(',').any(|badge| {
+            badge.starts_with("moderator/")
+                || badge.starts_with("vip/")
+                || badge.starts_with("broadcaster/")
+        });
+        let _ = self.push(Outgoing::Privileged {
+            channel: channel.to_owned(),
+            privileged,
+        });
+    }
+
+    /// Updates the slow mode of the channel, from its ROOMSTATE.
+    pub fn set_slow_mode(&self, channel: &str, secs: u64) {
+        let _ = self.push(Outgoing::SlowMode {
+            channel: channel.to_owned(),
+            secs,
+        });
+    }
+
+    fn push(&self, outgoing: Outgoing) -> Result<(), BackendError> {
+        self.tx
+            .send(outgoing)
+            .map_err(|_| BackendError::from("The outgoing message queue is closed".to_owned()))
+    }
+}
+
+struct ChannelQueue {
+    /// The channel, `#` included, or [`WHISPERS`].
+    name: String,
+    privileged: bool,
+    slow_mode: Duration,
+    last_sent: Option<Instant>,
+    /// The last message as it was sent, marker included.
+    last_text: Option<String>,
+    r9k_bypass: bool,
+    pending: VecDeque<Pending>,
+}
+
+struct Pending {
+    queued: Instant,
+    text: String,
+    parent: Option<String>,
+    recipient: Option<String>,
+}
+
+impl ChannelQueue {
+    fn new(name: String, r9k_bypass: bool) -> ChannelQueue {
+        ChannelQueue {
+            name,
+            privileged: false,
+            slow_mode: Duration::from_secs(0),
+            last_sent: None,
+            last_text: None,
+            r9k_bypass,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+struct OutboxTask {
+    control: Control,
+    helix: Option<Helix>,
+    stale_after: Duration,
+    max_queue: usize,
+    /// Whether to bypass the duplicate message filter, unless overridden for the channel.
+    r9k_bypass: bool,
+    r9k_channels: HashMap<String, bool>,
+    r9k_marker: String,
+    /// When the messages of the current rate window were sent, oldest first.
+    sent: VecDeque<Instant>,
+    channels: HashMap<String, ChannelQueue>,
+}
+
+impl OutboxTask {
+    async fn run(mut self, mut rx: mpsc::UnboundedReceiver<Outgoing>) {
+        loop {
+            let wakeup = self.next_wakeup();
+            tokio::select! {
+                outgoing = rx.recv() => match outgoing {
+                    Some(outgoing) => self.handle(outgoing),
+                    None => break,
+                },
+                _ = tokio::time::delay_until(
+                    tokio::time::Instant::from_std(wakeup.unwrap_or_else(Instant::now))
+                ), if wakeup.is_some() => (),
+            }
+            self.flush().await;
+        }
+        log::info!("The outgoing message queue closed");
+    }
+
+    fn queue(&mut self, channel: &str) -> &mut ChannelQueue {
+        let key = channel_key(channel);
+        let r9k_bypass = self
+            .r9k_channels
+            .get(&key)
+            .copied()
+            .unwrap_or(self.r9k_bypass);
+        // The name ends up in the raw reply lines, so it's rebuilt from the key
+        let name = if channel == WHISPERS {
+            WHISPERS.to_owned()
+        } else {
+            format!("#{}", key)
+        };
+        self.channels
+            .entry(key)
+            .or_insert_with(|| ChannelQueue::new(name, r9k_bypass))
+    }
+
+    fn handle(&mut self, outgoing: Outgoing) {
+        match outgoing {
+            Outgoing::Message {
+                channel,
+                text,
+                parent,
+                recipient,
+            } => {
+                let max_queue = self.max_queue;
+                let queue = self.queue(&channel);
+                // Without the bypass, a message identical to the last one waiting
+                // would only be dropped by Twitch
+                if !queue.r9k_bypass
+                    && queue
+                        .pending
+                        .back()
+                        .map(|last| last.text == text && last.recipient == recipient)
+                        == Some(true)
+                {
+                    log::debug!("Coalesced a repeated message to {}", channel);
+                    return;
+                }
+                while queue.pending.len() >= max_queue.max(1) {
+                    if let Some(dropped) = queue.pending.pop_front() {
+                        log::warn!("The queue of {} is full, dropped: {}", channel, dropped.text);
+                    }
+                }
+                queue.pending.push_back(Pending {
+                    queued: Instant::now(),
+                    text,
+                    parent,
+                    recipient,
+                });
+            }
+            Outgoing::Privileged {
+                channel,
+                privileged,
+            } => {
+                let queue = self.queue(&channel);
+                if queue.privileged != privileged {
+                    log::info!("Moderator or VIP in {}: {}", channel, privileged);
+                }
+                queue.privileged = privileged;
+            }
+            Outgoing::SlowMode { channel, secs } => {
+                self.queue(&channel).slow_mode = Duration::from_secs(secs);
+            }
+        }
+    }
+
+    /// When the next message of the channel may be sent.
+    fn ready_at(&self, queue: &ChannelQueue, now: Instant) -> Instant {
+        if queue.name == WHISPERS {
+            return queue
+                .last_sent
+                .map_or(now, |last| std::cmp::max(now, last + MIN_INTERVAL));
+        }
+        let rate = if queue.privileged {
+            PRIVILEGED_RATE
+        } else {
+            USER_RATE
+        };
+        let mut at = now;
+        if self.sent.len() >= rate {
+            at = std::cmp::max(at, self.sent[self.sent.len() - rate] + RATE_WINDOW);
+        }
+        if let (false, Some(last)) = (queue.privileged, queue.last_sent) {
+            at = std::cmp::max(at, last + std::cmp::max(queue.slow_mode, MIN_INTERVAL));
+        }
+        at
+    }
+
+    fn next_wakeup(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.channels
+            .values()
+            .filter(|queue| !queue.pending.is_empty())
+            .map(|queue| self.ready_at(queue, now))
+            .min()
+    }
+
+    /// Sends every message that's within the limits, oldest first.
+    async fn flush(&mut self) {
+        loop {
+            let now = Instant::now();
+            while let Some(sent) = self.sent.front() {
+                if now.duration_since(*sent) < RATE_WINDOW {
+                    break;
+                }
+                self.sent.pop_front();
+            }
+            let stale_after = self.stale_after;
+            for queue in self.channels.values_mut() {
+                while let Some(pending) = queue.pending.front() {
+                    if now.duration_since(pending.queued) < stale_after {
+                        break;
+                    }
+                    if let Some(stale) = queue.pending.pop_front() {
+                        log::warn!("Dropped a stale message to {}: {}", queue.name, stale.text);
+                    }
+                }
+            }
+
+            let next = self
+                .channels
+                .iter()
+                .filter(|(_, queue)| !queue.pending.is_empty())
+                .filter(|(_, queue)| self.ready_at(queue, now) <= now)
+                .min_by_key(|(_, queue)| queue.pending.front().map(|pending| pending.queued))
+                .map(|(key, _)| key.clone());
+            let queue = match next.and_then(|key| self.channels.get_mut(&key)) {
+                Some(queue) => queue,
+                None => return,
+            };
+
+            if let Some(Pending {
+                mut text,
+                parent,
+                recipient,
+                ..
+            }) = queue.pending.pop_front()
+            {
+                if let Some(user) = recipient {
+                    queue.last_sent = Some(now);
+                    if let Some(helix) = self.helix.clone() {
+                        // Sent apart so a slow request doesn't hold up the chat messages
+                        tokio::spawn(async move {
+                            if let Err(e) = helix.whisper(&user, &text).await {
+                                log::error!("Failed to whisper {}: {}", user, e);
+                            }
+                        });
+                    }
+                    continue;
+                }
+
+                // A message at the length limit has no room for the marker, Twitch drops it either way
+                if queue.r9k_bypass
+                    && queue.last_text.as_ref() == Some(&text)
+                    && text.chars().count() + self.r9k_marker.chars().count()
+                        <= util::MAX_MESSAGE_LENGTH
+                {
+                    text.push_str(&self.r9k_marker);
+                }
+                queue.last_text = Some(text.clone());
+                queue.last_sent = Some(now);
+                self.sent.push_back(now);
+                let mut writer = self.control.writer();
+                let result = match parent {
+                    // twitchchat can't attach tags to a PRIVMSG
+                    Some(parent) => {
+                        let line =
+                            format!("@reply-parent-msg-id={} PRIVMSG {} :{}", parent, queue.name, text);
+                        writer.raw(&line).await
+                    }
+                    None => writer.privmsg(&queue.name, text).await,
+                };
+                if let Err(e) = result {
+                    log::error!(
+                        "Caught a critical error while sending a response to the channel {}: {:?}",
+                        queue.name,
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::BackendError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Once, RwLock};
+
+static UNTAGGED: Once = Once::new();
+
+/// The permission level required to run a command, ordered from the least to the most privileged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission {
+    Everyone,
+    Subscriber,
+    Vip,
+    Moderator,
+    Broadcaster,
+    BotAdmin,
+}
+
+impl Default for Permission {
+    fn default() -> Self {
+        Permission::Everyone
+    }
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Permission::Everyone => "everyone",
+            Permission::Subscriber => "subscriber",
+            Permission::Vip => "vip",
+            Permission::Moderator => "moderator",
+            Permission::Broadcaster => "broadcaster",
+            Permission::BotAdmin => "bot-admin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for Permission {
+    type Err = BackendError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "everyone" => Permission::Everyone,
+            "subscriber" | "sub" => Permission::Subscriber,
+            "vip" => Permission::Vip,
+            "moderator" | "mod" => Permission::Moderator,
+            "broadcaster" => Permission::Broadcaster,
+            "bot-admin" | "admin" => Permission::BotAdmin,
+            _ => return Err(BackendError::from(format!("Unknown permission `{}`", s))),
+        })
+    }
+}
+
+/// The roles of a caller that come with a permission level.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Roles {
+    pub broadcaster: bool,
+    pub moderator: bool,
+    pub vip: bool,
+    pub subscriber: bool,
+}
+
+impl Roles {
+    /// Reads the roles from the badges of the message.
+    /// The badges come from the message tags, so without the tags capability everyone is `Everyone`.
+    pub fn of(evt: &twitchchat::messages::Privmsg<'_>) -> Roles {
+        if !evt.raw.starts_with('@') {
+            UNTAGGED.call_once(|| {
+                log::warn!(
+                    "Messages arrive without tags, the permission levels can't be determined"
+                )
+            });
+        }
+        Roles {
+            broadcaster: evt.is_broadcaster(),
+            moderator: evt.is_moderator(),
+            vip: evt.is_vip(),
+            subscriber: evt.is_subscriber(),
+        }
+    }
+
+    /// The highest level of the roles.
+    pub fn level(self, is_admin: bool) -> Permission {
+        if is_admin {
+            Permission::BotAdmin
+        } else if self.broadcaster {
+            Permission::Broadcaster
+        } else if self.moderator {
+            Permission::Moderator
+        } else if self.vip {
+            Permission::Vip
+        } else if self.subscriber {
+            Permission::Subscriber
+        } else {
+            Permission::Everyone
+        }
+    }
+}
+
+/// A per-user exception to a command's permission level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Override {
+    Grant,
+    Deny,
+}
+
+/// Persistent per-user overrides, stored as `{ "user": { "command path": "grant" | "deny" } }`.
+#[derive(Debug, Default)]
+pub struct Permissions {
+    path: String,
+    overrides: HashMap<String, HashMap<String, Override>>,
+}
+
+/// The overrides, shared with the scripts.
+pub type SharedPermissions = Arc<RwLock<Permissions>>;
+
+impl Permissions {
+    /// Loads the overrides from the given file. A missing file means there are no overrides.
+    pub fn load(path: &str) -> Result<Permissions, BackendError> {
+        let overrides = match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| {
+                BackendError::from(format!("Failed to parse the permissions at {}: {}", path, e))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                return Err(BackendError::from(format!(
+                    "Failed to read the permissions at {}: {}",
+                    path, e
+                )))
+            }
+        };
+        Ok(Permissions {
+            path: path.to_owned(),
+            overrides,
+        })
+    }
+
+    fn save(&self) -> Result<(), BackendError> {
+        let json = serde_json::to_string_pretty(&self.overrides)
+            .map_err(|e| BackendError::from(format!("Failed to serialize permissions: {}", e)))?;
+        std::fs::write(&self.path, json).map_err(|e| {
+            BackendError::from(format!(
+                "Failed to write the permissions to {}: {}",
+                self.path, e
+            ))
+        })
+    }
+
+    /// Checks whether the user may run the command. The most specific override wins,
+    /// so denying `song` also denies `song queue` unless `song queue` is granted explicitly.
+    /// Bot admins can't be locked out.
+    pub fn allows(&self, user: &str, command: &str, required: Permission, level: Permission) -> bool {
+        if level == Permission::BotAdmin {
+            return true;
+        }
+        if let Some(overrides) = self.overrides.get(&user.to_lowercase()) {
+            let tokens = command.split_whitespace().collect::<Vec<_>>();
+            for i in (1..=tokens.len()).rev() {
+                match overrides.get(&tokens[..i].join(" ")) {
+                    Some(Override::Grant) => return true,
+                    Some(Override::Deny) => return false,
+                    None => (),
+                }
+            }
+        }
+        level >= required
+    }
+
+    /// Sets or removes (`None`) an override and persists the change.
+    pub fn set(
+        &mut self,
+        user: &str,
+        command: &str,
+        value: Option<Override>,
+    ) -> Result<(), BackendError> {
+        let user = user.trim_start_matches('@').to_lowercase();
+        match value {
+            Some(value) => {
+                self.overrides
+                    .entry(user)
+                    .or_default()
+                    .insert(command.to_owned(), value);
+            }
+            None => {
+                if let Some(overrides) = self.overrides.get_mut(&user) {
+                    overrides.remove(command);
+                    if overrides.is_empty() {
+                        self.overrides.remove(&user);
+                    }
+                }
+            }
+        }
+        self.save()
+    }
+}
@@ -0,0 +1,229 @@
+//! Per-channel configuration profiles.
+//!
+//! The settings of a channel come from, in order of precedence, the edits made from chat
+//! (persisted in the store), the channel's `[channel.<name>]` section in `bot.toml`,
+//! and the global settings.
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use super::config::{channel_key, BotConfig, ChannelConfig, Concurrency, Prefix};
+use crate::lua::store::Store;
+use crate::BackendError;
+
+/// The store namespace of the edits made from chat.
+const NAMESPACE: &str = "bot:profiles";
+
+/// The settings that can be edited from chat.
+pub const EDITABLE: &[&str] = &[
+    "prefixes",
+    "mention",
+    "max_concurrent",
+    "ordered_replies",
+    "greeting",
+    "language",
+    "cooldown_multiplier",
+    "enabled",
+    "disabled",
+    "streamelements_id",
+    "staff",
+];
+
+/// The largest accepted cooldown multiplier.
+pub const MAX_COOLDOWN_MULTIPLIER: f64 = 100.0;
+
+/// The resolved settings of a channel.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub prefix: Prefix,
+    pub concurrency: Concurrency,
+    pub greeting: Option<String>,
+    pub language: String,
+    pub cooldown_multiplier: f64,
+    pub enabled: Option<Vec<String>>,
+    pub disabled: Vec<String>,
+    pub streamelements_id: Option<String>,
+    pub staff: HashSet<String>,
+}
+
+impl Profile {
+    /// Checks whether the command can be used in the channel.
+    /// Enabling or disabling a command applies to its subcommands too.
+    pub fn is_enabled(&self, command: &str) -> bool {
+        let matches = |names: &[String]| {
+            names.iter().any(|name| {
+                command == name
+                    || (command.starts_with(name.as_str())
+                        && command[name.len()..].starts_with(' '))
+            })
+        };
+        if matches(&self.disabled) {
+            return false;
+        }
+        self.enabled.as_ref().map(|e| matches(e)).unwrap_or(true)
+    }
+}
+
+pub struct Profiles {
+    defaults: ChannelConfig,
+    configured: HashMap<String, ChannelConfig>,
+    edits: HashMap<String, ChannelConfig>,
+    store: Store,
+}
+
+/// The profiles, shared with the scripts.
+pub type SharedProfiles = Arc<RwLock<Profiles>>;
+
+impl Profiles {
+    /// Loads the channel sections of the config and the edits persisted in the store.
+    pub fn load(config: &BotConfig, store: &Store) -> Result<Profiles, BackendError> {
+        let store = store.scoped(NAMESPACE);
+        let mut edits = HashMap::new();
+        for (channel, value) in store.list("")? {
+            match serde_json::from_value::<ChannelConfig>(value) {
+                Ok(mut edit) => {
+                    if let Err(e) = check_multiplier(edit.cooldown_multiplier) {
+                        log::error!("Ignoring the cooldown multiplier of {}: {}", channel, e);
+                        edit.cooldown_multiplier = None;
+                    }
+                    edits.insert(channel, edit);
+                }
+                Err(e) => log::error!("Ignoring the broken profile of {}: {}", channel, e),
+            }
+        }
+
+        let defaults = config.defaults();
+        check_multiplier(defaults.cooldown_multiplier)?;
+        let mut configured = HashMap::new();
+        for (name, channel) in &config.channel {
+            check_multiplier(channel.cooldown_multiplier).map_err(|e| {
+                BackendError::from(format!("Invalid config of channel {}: {}", name, e))
+            })?;
+            configured.insert(channel_key(name), channel.clone());
+        }
+
+        Ok(Profiles {
+            defaults,
+            configured,
+            edits,
+            store,
+        })
+    }
+
+    /// Resolves the settings of the channel.
+    pub fn get(&self, channel: &str) -> Profile {
+        let key = channel_key(channel);
+        let empty = ChannelConfig::default();
+        let channel = self
+            .edits
+            .get(&key)
+            .unwrap_or(&empty)
+            .or(self.configured.get(&key).unwrap_or(&empty));
+
+        // The channel's disabled commands add to the global ones instead of replacing them
+        let mut disabled = self.defaults.disabled.clone().unwrap_or_default();
+        disabled.extend(channel.disabled.clone().unwrap_or_default());
+        disabled.sort();
+        disabled.dedup();
+
+        let config = channel.or(&self.defaults);
+
+        Profile {
+            prefix: Prefix {
+                prefixes: config.prefixes.unwrap_or_default(),
+                mention: config.mention.unwrap_or(false),
+            },
+            concurrency: Concurrency {
+                // A limit of 0 would never run anything
+                limit: config.max_concurrent.unwrap_or(1).max(1),
+                ordered: config.ordered_replies.unwrap_or(true),
+            },
+            greeting: config.greeting.filter(|greeting| !greeting.is_empty()),
+            language: config.language.unwrap_or_default(),
+            cooldown_multiplier: config.cooldown_multiplier.unwrap_or(1.0),
+            enabled: config.enabled,
+            disabled,
+            streamelements_id: config.streamelements_id,
+            staff: config
+                .staff
+                .unwrap_or_default()
+                .into_iter()
+                .map(|user| user.to_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Edits (or with `None`, resets) a setting of the channel and persists the change.
+    pub fn set(&mut self, channel: &str, key: &str, value: Option<&str>) -> Result<(), BackendError> {
+        let channel = channel_key(channel);
+        let mut edit = self.edits.get(&channel).cloned().unwrap_or_default();
+        let list = |value: &str| {
+            value
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|item| !item.is_empty())
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+
+        match key {
+            "prefixes" => {
+                edit.prefixes = value.map(|v| v.split_whitespace().map(str::to_owned).collect())
+            }
+            "mention" => edit.mention = value.map(parse).transpose()?,
+            "max_concurrent" => edit.max_concurrent = value.map(parse).transpose()?,
+            "ordered_replies" => edit.ordered_replies = value.map(parse).transpose()?,
+            "greeting" => {
+                edit.greeting = value.map(|v| if v == "none" { "" } else { v }.to_owned())
+            }
+            "language" => edit.language = value.map(str::to_owned),
+            "cooldown_multiplier" => {
+                edit.cooldown_multiplier = value.map(parse::<f64>).transpose()?;
+                check_multiplier(edit.cooldown_multiplier)?;
+            }
+            "enabled" => edit.enabled = value.map(list),
+            "disabled" => edit.disabled = value.map(list),
+            "streamelements_id" => edit.streamelements_id = value.map(str::to_owned),
+            "staff" => edit.staff = value.map(list),
+            other => {
+                return Err(BackendError::from(format!(
+                    "Unknown setting `{}`, expected one of: {}",
+                    other,
+                    EDITABLE.join(", ")
+                )))
+            }
+        }
+
+        if edit == ChannelConfig::default() {
+            self.store.delete(&channel)?;
+            self.edits.remove(&channel);
+        } else {
+            let json = serde_json::to_value(&edit).map_err(|e| {
+                BackendError::from(format!("Failed to serialize the profile: {}", e))
+            })?;
+            self.store.set(&channel, &json)?;
+            self.edits.insert(channel, edit);
+        }
+        Ok(())
+    }
+}
+
+/// Rejects the cooldown multipliers that would overflow the cooldowns.
+fn check_multiplier(multiplier: Option<f64>) -> Result<(), BackendError> {
+    match multiplier {
+        Some(m) if !m.is_finite() || m < 0.0 || m > MAX_COOLDOWN_MULTIPLIER => {
+            Err(BackendError::from(format!(
+                "The cooldown multiplier must be between 0 and {}, got {}",
+                MAX_COOLDOWN_MULTIPLIER, m
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, BackendError>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| BackendError::from(format!("Invalid value `{}`: {}", value, e)))
+}
@@ -0,0 +1,663 @@
+//! Scripts that run on a schedule, declared in `timers.json`:
+//!
+//! ```json
+//! {
+//!     "hydrate": {
+//!         "script": "scripts/timers/hydrate.lua",
+//!         "channels": ["moscowwbish"],
+//!         "interval": 30,
+//!         "live_only": true,
+//!         "min_messages": 5
+//!     }
+//! }
+//! ```
+//!
+//! A timer runs every `interval` minutes or on a `cron` expression (see `cron`) in each of its
+//! channels, optionally only while the channel is live and only once enough chat messages
+//! arrived since its last run. It receives a table with the `channel`, the `timer` and the
+//! `messages` since its last run, and a string it returns is sent to the channel.
+//!
+//! The scripts can schedule functions of their own with `bot:schedule`, up to
+//! `MAX_CALLBACKS` per command and at most `MAX_DELAY` ahead, and cancel them with
+//! `bot:unschedule`. All of them are listed and cancellable from chat with `timers`.
+use chrono::{DateTime, Utc};
+use mlua::ToLua;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::command::compile;
+use super::command::report::{LoadError, LoadErrorKind, LoadReport};
+use super::config::channel_key;
+use super::cron::Cron;
+use crate::lua::sandbox::Sandbox;
+use crate::BackendError;
+
+/// How many functions a command may have scheduled at once.
+pub const MAX_CALLBACKS: usize = 20;
+/// The longest delay a function can be scheduled after, 30 days.
+pub const MAX_DELAY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Deserialize)]
+struct TimerJSON {
+    script: String,
+    channels: Vec<String>,
+    /// The minutes between the runs.
+    interval: Option<u64>,
+    cron: Option<String>,
+    #[serde(default)]
+    live_only: bool,
+    #[serde(default)]
+    min_messages: u64,
+    sandbox: Option<Sandbox>,
+    timeout: Option<u64>,
+}
+
+/// The compiled script of a declared timer.
+#[derive(Clone)]
+pub struct TimerScript<'lua> {
+    /// The name of the timer in the logs and in `running`, e.g. `timer:hydrate`. The `:`
+    /// keeps its `store` apart from the ones of the commands.
+    pub id: String,
+    pub path: String,
+    pub sandbox: Sandbox,
+    /// How long the script may run in seconds, overriding the global timeout. `0` disables it.
+    pub timeout: Option<u64>,
+    pub script: mlua::Function<'lua>,
+}
+
+/// A declared timer in one of its channels.
+pub struct Declared {
+    pub name: String,
+    pub channel: String,
+    pub schedule: Schedule,
+    pub live_only: bool,
+    pub min_messages: u64,
+}
+
+/// Loads the timers file, collecting every error into the report like `load_commands`.
+/// A missing file means no timers. When reloading, the broken timers keep their `previous`
+/// working scripts, and a broken timers file keeps all of them, with no declared timers
+/// returned so that the running ones are left alone.
+pub fn load_timers<'lua>(
+    lua: &'lua mlua::Lua,
+    path: &str,
+    previous: Option<&HashMap<String, TimerScript<'lua>>>,
+) -> (
+    HashMap<String, TimerScript<'lua>>,
+    Option<Vec<Declared>>,
+    LoadReport,
+) {
+    let mut report = LoadReport::default();
+    let json = match std::fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return (HashMap::new(), Some(Vec::new()), report)
+        }
+        Err(e) => {
+            report
+                .errors
+                .push(LoadError::new(LoadErrorKind::MissingFile, e.to_string()).file(path));
+            return (previous.cloned().unwrap_or_default(), None, report);
+        }
+    };
+    let timers = match serde_json::from_str::<HashMap<String, TimerJSON>>(&json) {
+        Ok(timers) => timers,
+        Err(e) => {
+            report
+                .errors
+                .push(LoadError::new(LoadErrorKind::Schema, e.to_string()).file(path));
+            return (previous.cloned().unwrap_or_default(), None, report);
+        }
+    };
+
+    let mut scripts = HashMap::new();
+    let mut declared = Vec::new();
+    for (name, timer) in timers {
+        let id = format!("timer:{}", name);
+        let schedule = match (timer.interval, &timer.cron) {
+            (Some(minutes), None) if minutes > 0 => minutes
+                .checked_mul(60)
+                .map(Duration::from_secs)
+                .filter(|interval| *interval <= MAX_DELAY)
+                .map(Schedule::Every)
+                .ok_or_else(|| {
+                    format!(
+                        "The `interval` can be at most {} minutes",
+                        MAX_DELAY.as_secs() / 60
+                    )
+                }),
+            (None, Some(cron)) => cron.parse().map(Schedule::Cron).map_err(|e| e.to_string()),
+            _ => Err("Expected either a positive `interval` in minutes or a `cron`".to_owned()),
+        };
+        let schedule = match schedule {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                report
+                    .errors
+                    .push(LoadError::new(LoadErrorKind::Schema, e).command(&id));
+                continue;
+            }
+        };
+
+        let sandbox = timer.sandbox.unwrap_or_default();
+        let script = match compile(lua, &id, &timer.script, &sandbox) {
+            Ok(script) => script,
+            Err(e) => {
+                report.errors.push(e);
+                match previous.and_then(|p| p.get(&name)) {
+                    Some(previous) => {
+                        report.kept.push(id.clone());
+                        previous.script.clone()
+                    }
+                    None => continue,
+                }
+            }
+        };
+        for channel in &timer.channels {
+            declared.push(Declared {
+                name: name.clone(),
+                channel: format!("#{}", channel_key(channel)),
+                schedule: schedule.clone(),
+                live_only: timer.live_only,
+                min_messages: timer.min_messages,
+            });
+        }
+        scripts.insert(
+            name,
+            TimerScript {
+                id,
+                path: timer.script,
+                sandbox,
+                timeout: timer.timeout,
+                script,
+            },
+        );
+    }
+    (scripts, Some(declared), report)
+}
+
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// Once, after the delay.
+    After(Duration),
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// When the task runs next, or `None` if it's done.
+    fn next(&self, now: DateTime<Utc>, first: bool) -> Option<DateTime<Utc>> {
+        match self {
+            Schedule::After(delay) if first => now.checked_add_signed(to_chrono(*delay)?),
+            Schedule::After(_) => None,
+            Schedule::Every(interval) => now.checked_add_signed(to_chrono(*interval)?),
+            Schedule::Cron(cron) => cron.next_after(now),
+        }
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Schedule::After(delay) => write!(f, "once after {}s", delay.as_secs()),
+            Schedule::Every(interval) => write!(f, "every {}m", interval.as_secs() / 60),
+            Schedule::Cron(cron) => write!(f, "cron {}", cron),
+        }
+    }
+}
+
+/// Converts the duration, `None` if it's out of chrono's range.
+fn to_chrono(duration: Duration) -> Option<chrono::Duration> {
+    chrono::Duration::from_std(duration).ok()
+}
+
+enum Action {
+    /// A declared timer, by name.
+    Script {
+        name: String,
+        live_only: bool,
+        min_messages: u64,
+        /// The channel's message count as of the last run.
+        seen: u64,
+    },
+    /// A function scheduled by a script of the main Lua state.
+    Callback {
+        function: Arc<mlua::RegistryKey>,
+        /// The command that scheduled it, the only one that can cancel it.
+        owner: String,
+    },
+}
+
+struct Task {
+    label: String,
+    channel: Option<String>,
+    schedule: Schedule,
+    next: DateTime<Utc>,
+    action: Action,
+}
+
+/// A task as listed in chat.
+pub struct TaskInfo {
+    pub id: u64,
+    pub label: String,
+    pub channel: Option<String>,
+    pub schedule: String,
+    pub next: DateTime<Utc>,
+}
+
+/// A task that's due to run.
+pub struct Due {
+    pub id: u64,
+    pub label: String,
+    pub channel: Option<String>,
+    pub run: DueAction,
+}
+
+pub enum DueAction {
+    Script {
+        name: String,
+        live_only: bool,
+        messages: u64,
+        /// The channel's message count to mark as seen once it runs, see `Scheduler::mark_seen`.
+        count: u64,
+    },
+    Callback(Arc<mlua::RegistryKey>),
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    tasks: BTreeMap<u64, Task>,
+    /// The chat messages seen per channel.
+    messages: HashMap<String, u64>,
+}
+
+/// The scheduled tasks, shared with the scripts.
+#[derive(Clone)]
+pub struct Scheduler {
+    state: Arc<Mutex<State>>,
+    /// Wakes the bot up when the schedule changes.
+    wake: mpsc::UnboundedSender<()>,
+}
+
+impl Scheduler {
+    /// Creates the scheduler along with the receiver of its wake-ups.
+    pub fn new() -> (Scheduler, mpsc::UnboundedReceiver<()>) {
+        let (wake, rx) = mpsc::unbounded_channel();
+        let scheduler = Scheduler {
+            state: Arc::new(Mutex::new(State::default())),
+            wake,
+        };
+        (scheduler, rx)
+    }
+
+    fn add(
+        &self,
+        label: String,
+        channel: Option<String>,
+        schedule: Schedule,
+        action: Action,
+    ) -> Result<u64, BackendError> {
+        let next = schedule
+            .next(Utc::now(), true)
+            .ok_or_else(|| BackendError::from(format!("`{}` never runs", schedule)))?;
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.tasks.insert(
+            id,
+            Task {
+                label,
+                channel,
+                schedule,
+                next,
+                action,
+            },
+        );
+        let _ = self.wake.send(());
+        Ok(id)
+    }
+
+    /// Schedules a function of the main Lua state on behalf of the `owner` command,
+    /// replying to the channel if given.
+    pub fn add_callback(
+        &self,
+        owner: &str,
+        channel: Option<String>,
+        schedule: Schedule,
+        function: mlua::RegistryKey,
+    ) -> Result<u64, BackendError> {
+        if let Schedule::After(delay) = schedule {
+            if delay > MAX_DELAY {
+                return Err(BackendError::from(format!(
+                    "Functions can be scheduled at most {} days ahead",
+                    MAX_DELAY.as_secs() / (24 * 60 * 60)
+                )));
+            }
+        }
+        let scheduled = self
+            .state
+            .lock()
+            .unwrap()
+            .tasks
+            .values()
+            .filter(|task| match &task.action {
+                Action::Callback { owner: o, .. } => o == owner,
+                Action::Script { .. } => false,
+            })
+            .count();
+        if scheduled >= MAX_CALLBACKS {
+            return Err(BackendError::from(format!(
+                "`{}` already has {} scheduled functions",
+                owner, MAX_CALLBACKS
+            )));
+        }
+        self.add(
+            "scheduled function".to_owned(),
+            channel,
+            schedule,
+            Action::Callback {
+                function: Arc::new(function),
+                owner: owner.to_owned(),
+            },
+        )
+    }
+
+    /// Replaces the declared timers, keeping the functions scheduled by the scripts.
+    pub fn set_declared(&self, declared: Vec<Declared>) {
+        self.state
+            .lock()
+            .unwrap()
+            .tasks
+            .retain(|_, task| matches!(task.action, Action::Callback { .. }));
+        for timer in declared {
+            let seen = self.message_count(&timer.channel);
+            let added = self.add(
+                format!("{} timer", timer.name),
+                Some(timer.channel.clone()),
+                timer.schedule,
+                Action::Script {
+                    name: timer.name,
+                    live_only: timer.live_only,
+                    min_messages: timer.min_messages,
+                    seen,
+                },
+            );
+            if let Err(e) = added {
+                log::error!("Failed to schedule a timer in {}: {}", timer.channel, e);
+            }
+        }
+    }
+
+    pub fn cancel(&self, id: u64) -> Option<String> {
+        let task = self.state.lock().unwrap().tasks.remove(&id)?;
+        let _ = self.wake.send(());
+        Some(task.label)
+    }
+
+    /// Cancels a function scheduled by the `owner` command. The declared timers and
+    /// the functions of the other commands are left alone.
+    pub fn cancel_owned(&self, id: u64, owner: &str) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        match &state.tasks.get(&id)?.action {
+            Action::Callback { owner: o, .. } if o == owner => (),
+            _ => return None,
+        }
+        let task = state.tasks.remove(&id)?;
+        let _ = self.wake.send(());
+        Some(task.label)
+    }
+
+    /// Marks the channel's messages up to `count` as seen by the declared timer,
+    /// once it actually runs.
+    pub fn mark_seen(&self, id: u64, count: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(Task {
+            action: Action::Script { seen, .. },
+            ..
+        }) = state.tasks.get_mut(&id)
+        {
+            *seen = std::cmp::max(*seen, count);
+        }
+    }
+
+    pub fn list(&self) -> Vec<TaskInfo> {
+        self.state
+            .lock()
+            .unwrap()
+            .tasks
+            .iter()
+            .map(|(id, task)| TaskInfo {
+                id: *id,
+                label: task.label.clone(),
+                channel: task.channel.clone(),
+                schedule: task.schedule.to_string(),
+                next: task.next,
+            })
+            .collect()
+    }
+
+    /// Counts a chat message, for the timers waiting for enough of them.
+    pub fn record_message(&self, channel: &str) {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .messages
+            .entry(channel_key(channel))
+            .or_default() += 1;
+    }
+
+    fn message_count(&self, channel: &str) -> u64 {
+        let state = self.state.lock().unwrap();
+        state
+            .messages
+            .get(&channel_key(channel))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// When the next task is due.
+    pub fn next_due(&self) -> Option<DateTime<Utc>> {
+        let state = self.state.lock().unwrap();
+        state.tasks.values().map(|task| task.next).min()
+    }
+
+    /// Takes the tasks due by now and schedules their next runs. A declared timer without
+    /// enough messages since its last run skips its turn. The messages of a `live_only` timer
+    /// are only marked as seen by `mark_seen`, once it's known to run.
+    pub fn take_due(&self, now: DateTime<Utc>) -> Vec<Due> {
+        let mut state = self.state.lock().unwrap();
+        let State {
+            tasks, messages, ..
+        } = &mut *state;
+
+        let mut due = Vec::new();
+        let mut done = Vec::new();
+        for (id, task) in tasks.iter_mut().filter(|(_, task)| task.next <= now) {
+            match task.schedule.next(now, false) {
+                Some(next) => task.next = next,
+                None => done.push(*id),
+            }
+            let run = match &mut task.action {
+                Action::Script {
+                    name,
+                    live_only,
+                    min_messages,
+                    seen,
+                } => {
+                    let channel = task.channel.as_deref().map(channel_key).unwrap_or_default();
+                    let count = messages.get(&channel).copied().unwrap_or(0);
+                    if count - *seen < *min_messages {
+                        log::debug!(
+                            "Skipped the {} in {}, chat is too quiet",
+                            task.label,
+                            channel
+                        );
+                        continue;
+                    }
+                    let run = DueAction::Script {
+                        name: name.clone(),
+                        live_only: *live_only,
+                        messages: count - *seen,
+                        count,
+                    };
+                    if !*live_only {
+                        *seen = count;
+                    }
+                    run
+                }
+                Action::Callback { function, .. } => DueAction::Callback(function.clone()),
+            };
+            due.push(Due {
+                id: *id,
+                label: task.label.clone(),
+                channel: task.channel.clone(),
+                run,
+            });
+        }
+        for id in done {
+            tasks.remove(&id);
+        }
+        due
+    }
+}
+
+/// Waits until the next task is due or the schedule changes.
+pub async fn next_tick(scheduler: &Scheduler, wake: &mut mpsc::UnboundedReceiver<()>) {
+    let delay = scheduler
+        .next_due()
+        .map(|next| (next - Utc::now()).to_std().unwrap_or_default());
+    tokio::select! {
+        _ = tokio::time::delay_for(delay.unwrap_or_default()), if delay.is_some() => (),
+        _ = wake.recv() => (),
+    }
+}
+
+/// The table a timer script receives.
+#[derive(Debug, Clone)]
+pub struct Tick {
+    pub channel: String,
+    pub timer: String,
+    pub messages: u64,
+}
+
+impl<'lua> ToLua<'lua> for Tick {
+    fn to_lua(self, lua: &'lua mlua::Lua) -> mlua::Result<mlua::Value<'lua>> {
+        let table = lua.create_table()?;
+        table.set("channel", self.channel)?;
+        table.set("timer", self.timer)?;
+        table.set("messages", self.messages)?;
+        Ok(mlua::Value::Table(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn declare(scheduler: &Scheduler, live_only: bool, min_messages: u64) -> u64 {
+        scheduler.set_declared(vec![Declared {
+            name: "hydrate".to_owned(),
+            channel: "#moscowwbish".to_owned(),
+            schedule: Schedule::Every(Duration::from_secs(60)),
+            live_only,
+            min_messages,
+        }]);
+        let timers = scheduler.list();
+        timers
+            .iter()
+            .find(|task| task.label == "hydrate timer")
+            .unwrap()
+            .id
+    }
+
+    fn later(minutes: i64) -> DateTime<Utc> {
+        Utc::now() + chrono::Duration::minutes(minutes) + chrono::Duration::seconds(1)
+    }
+
+    fn messages(due: &[Due]) -> Vec<u64> {
+        due.iter()
+            .filter_map(|due| match due.run {
+                DueAction::Script { messages, .. } => Some(messages),
+                DueAction::Callback(_) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn take_due_only_takes_the_due_tasks() {
+        let (scheduler, _wake) = Scheduler::new();
+        declare(&scheduler, false, 0);
+        assert!(scheduler.take_due(Utc::now()).is_empty());
+        let due = scheduler.take_due(later(1));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].channel.as_deref(), Some("#moscowwbish"));
+        // Rescheduled an interval after the run
+        assert!(scheduler.take_due(later(1)).is_empty());
+        assert_eq!(scheduler.take_due(later(2)).len(), 1);
+    }
+
+    #[test]
+    fn take_due_skips_quiet_chats() {
+        let (scheduler, _wake) = Scheduler::new();
+        declare(&scheduler, false, 2);
+        scheduler.record_message("#moscowwbish");
+        assert!(scheduler.take_due(later(1)).is_empty());
+        scheduler.record_message("MoscowWbish");
+        assert_eq!(messages(&scheduler.take_due(later(2))), vec![2]);
+        // The messages were seen by the last run
+        assert!(scheduler.take_due(later(3)).is_empty());
+    }
+
+    #[test]
+    fn live_only_timers_see_the_messages_once_they_run() {
+        let (scheduler, _wake) = Scheduler::new();
+        let id = declare(&scheduler, true, 1);
+        scheduler.record_message("#moscowwbish");
+        // Offline, so never marked as seen
+        assert_eq!(messages(&scheduler.take_due(later(1))), vec![1]);
+        let due = scheduler.take_due(later(2));
+        assert_eq!(messages(&due), vec![1]);
+        scheduler.mark_seen(id, 1);
+        assert!(scheduler.take_due(later(3)).is_empty());
+    }
+
+    #[test]
+    fn callbacks_are_limited_and_owned() {
+        let lua = mlua::Lua::new();
+        let (scheduler, _wake) = Scheduler::new();
+        let add = |owner: &str, schedule: Schedule| {
+            let function = lua.create_registry_value(0).unwrap();
+            scheduler.add_callback(owner, None, schedule, function)
+        };
+        let minute = Duration::from_secs(60);
+        assert!(add("ping", Schedule::After(MAX_DELAY + minute)).is_err());
+        let id = add("ping", Schedule::After(minute)).unwrap();
+        for _ in 1..MAX_CALLBACKS {
+            add("ping", Schedule::After(minute)).unwrap();
+        }
+        assert!(add("ping", Schedule::After(minute)).is_err());
+        assert!(add("song", Schedule::After(minute)).is_ok());
+
+        let timer = declare(&scheduler, false, 0);
+        assert!(scheduler.cancel_owned(timer, "ping").is_none());
+        assert!(scheduler.cancel_owned(id, "song").is_none());
+        assert!(scheduler.cancel_owned(id, "ping").is_some());
+        assert!(add("ping", Schedule::After(minute)).is_ok());
+    }
+
+    #[test]
+    fn huge_schedules_never_run_instead_of_panicking() {
+        let now = Utc::now();
+        assert!(Schedule::After(Duration::from_secs(u64::MAX))
+            .next(now, true)
+            .is_none());
+        assert!(Schedule::Every(Duration::from_secs(u64::MAX))
+            .next(now, false)
+            .is_none());
+    }
+}
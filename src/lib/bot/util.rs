@@ -1,25 +1,94 @@
-use super::command::{Command, CommandData};
+use super::command::report::{LoadError, LoadErrorKind};
+use super::command::{Command, CommandData, ScriptArgs};
+use super::config::Prefix;
 use crate::{BackendError, BoxedError};
 use serde::Deserialize;
 use serde_json::from_str;
 use std::collections::HashMap;
-use std::iter::FromIterator;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// The maximum length of a Twitch chat message in characters.
+pub const MAX_MESSAGE_LENGTH: usize = 500;
+
+/// The tags of a PRIVMSG passed to the scripts.
+const PRIVMSG_TAGS: &[&str] = &[
+    "badge-info",
+    "badges",
+    "bits",
+    "client-nonce",
+    "color",
+    "display-name",
+    "emote-only",
+    "emotes",
+    "first-msg",
+    "flags",
+    "id",
+    "mod",
+    "reply-parent-display-name",
+    "reply-parent-msg-body",
+    "reply-parent-msg-id",
+    "reply-parent-user-id",
+    "reply-parent-user-login",
+    "returning-chatter",
+    "room-id",
+    "subscriber",
+    "tmi-sent-ts",
+    "turbo",
+    "user-id",
+    "user-type",
+    "vip",
+];
 
 pub fn format_args(
     evt: &twitchchat::messages::Privmsg,
+    command: &CommandData,
     args: Option<Vec<&str>>,
-) -> mlua::Variadic<String> {
-    let header = vec![evt.channel.to_string(), evt.name.to_string()];
-    match args {
-        Some(args) => mlua::Variadic::from_iter(
-            header
-                .into_iter()
-                .chain(args.into_iter().map(|it| it.to_owned())),
-        ),
-        None => mlua::Variadic::from_iter(header.into_iter()),
+) -> ScriptArgs {
+    let tags = PRIVMSG_TAGS
+        .iter()
+        .filter_map(|&key| {
+            let value = evt.tags.get(key)?;
+            Some((key.to_owned(), unescape_tag(&value)))
+        })
+        .collect::<HashMap<_, _>>();
+    ScriptArgs {
+        message_id: tags.get("id").cloned(),
+        tags,
+        message: evt.data.to_string(),
+        command: command.id.clone(),
+        context: command.context,
+        channel: evt.channel.to_string(),
+        user: evt.name.to_string(),
+        args: args
+            .unwrap_or_default()
+            .into_iter()
+            .map(|it| it.to_owned())
+            .collect(),
+        parsed: None,
     }
 }
 
+/// Reverts the escaping of an IRCv3 tag value.
+fn unescape_tag(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => (),
+        }
+    }
+    unescaped
+}
+
 pub fn strip_prefix<'a>(str: &'a str, prefix: &str) -> &'a str {
     if !str.starts_with(prefix) {
         &str[..]
@@ -28,18 +97,73 @@ pub fn strip_prefix<'a>(str: &'a str, prefix: &str) -> &'a str {
     }
 }
 
-pub fn load_file(path: &str) -> Result<String, BackendError> {
+/// Strips the channel's command prefix (or a mention of the bot, if enabled) from the message.
+/// Returns `None` if the message isn't addressed to the bot.
+pub fn strip_command_prefix<'a>(
+    message: &'a str,
+    prefix: &Prefix,
+    nickname: Option<&str>,
+) -> Option<&'a str> {
+    for p in prefix.prefixes.iter().filter(|p| !p.is_empty()) {
+        if !message.starts_with(p.as_str()) {
+            continue;
+        }
+        let rest = &message[p.len()..];
+        // Word-like prefixes such as `xD` must be followed by a space, so that `xDD` doesn't trigger
+        let needs_boundary = p.chars().last().map(char::is_alphanumeric).unwrap_or(false);
+        if needs_boundary && !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            continue;
+        }
+        return Some(rest.trim());
+    }
+
+    if prefix.mention {
+        if let Some(nickname) = nickname {
+            return strip_mention(message, nickname);
+        }
+    }
+
+    None
+}
+
+/// Strips a leading mention of the bot: `@aniki`, `aniki,` or `aniki:`.
+pub fn strip_mention<'a>(message: &'a str, nickname: &str) -> Option<&'a str> {
+    let message = message.trim_start();
+    let has_at = message.starts_with('@');
+    let word = message.trim_start_matches('@');
+    let end = word
+        .find(|c: char| c.is_whitespace() || c == ',' || c == ':')
+        .unwrap_or_else(|| word.len());
+    let (name, rest) = word.split_at(end);
+    let addressed = has_at || rest.starts_with(',') || rest.starts_with(':');
+    if addressed && name.eq_ignore_ascii_case(nickname) {
+        Some(rest.trim_start_matches(|c| c == ',' || c == ':').trim())
+    } else {
+        None
+    }
+}
+
+/// Reads a script, transpiling it to Lua if it's a `.ppga` file.
+pub fn read_script(path: &str) -> Result<String, LoadError> {
     let source = std::fs::read_to_string(path).map_err(|e| {
-        BackendError::from(format!("Failed to read the lua file at `{}`: {}.", path, e))
+        LoadError::new(
+            LoadErrorKind::MissingFile,
+            format!("Failed to read the lua file at `{}`: {}.", path, e),
+        )
+        .file(path)
     })?;
     if path.ends_with(".ppga") {
         ppga::ppga_to_lua(&source, ppga::PPGAConfig::default())
-            .map_err(|ex| BackendError::from(ex.report_to_string()))
+            .map_err(|ex| LoadError::new(LoadErrorKind::Transpile, ex.report_to_string()).file(path))
     } else {
         Ok(source)
     }
 }
 
+pub fn load_file(path: &str) -> Result<String, BackendError> {
+    read_script(path).map_err(|e| BackendError::from(e.message))
+}
+
 pub fn parse_json<'a, R>(json: &'a str) -> Result<R, BackendError>
 where
     R: Deserialize<'a>,
@@ -71,6 +195,39 @@ pub fn duration_format(duration: chrono::Duration) -> String {
     output
 }
 
+/// Looks up a command by its name or one of its aliases.
+pub fn get_command<'c, 'lua>(
+    commands: &'c HashMap<String, Command<'lua>>,
+    name: &str,
+) -> Option<&'c Command<'lua>> {
+    commands
+        .get(name)
+        .or_else(|| commands.values().find(|c| c.aliases.iter().any(|a| a == name)))
+}
+
+/// Looks up a command by its name or one of its aliases, mutably.
+pub fn get_command_mut<'c, 'lua>(
+    commands: &'c mut HashMap<String, Command<'lua>>,
+    name: &str,
+) -> Option<&'c mut Command<'lua>> {
+    if commands.contains_key(name) {
+        return commands.get_mut(name);
+    }
+    commands
+        .values_mut()
+        .find(|c| c.aliases.iter().any(|a| a == name))
+}
+
+/// Returns the message without its first `n` words, keeping the whitespace between the others.
+pub fn skip_words(message: &str, n: usize) -> &str {
+    let mut rest = message.trim_start();
+    for _ in 0..n {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest
+}
+
 pub fn find_command<'a, 'lua>(
     commands: &HashMap<String, Command<'lua>>,
     name: &'a str,
@@ -79,7 +236,7 @@ pub fn find_command<'a, 'lua>(
     let mut next_commands = commands;
 
     for i in 0..tokens.len() {
-        if let Some(command) = next_commands.get(tokens[i]) {
+        if let Some(command) = get_command(next_commands, tokens[i]) {
             let commands = command.commands.as_ref();
 
             let next = if i + 1 < tokens.len() {
@@ -88,7 +245,9 @@ pub fn find_command<'a, 'lua>(
                 None
             };
 
-            if next.is_some() && commands.is_some() && commands.unwrap().contains_key(next.unwrap())
+            if next.is_some()
+                && commands.is_some()
+                && get_command(commands.unwrap(), next.unwrap()).is_some()
             {
                 next_commands = match commands {
                     Some(a) => a,
@@ -116,6 +275,152 @@ pub fn find_command<'a, 'lua>(
     None
 }
 
+/// Finds the command node (scripted or not) at the given path, e.g. `song queue`.
+pub fn find_node<'c, 'lua>(
+    commands: &'c HashMap<String, Command<'lua>>,
+    path: &str,
+) -> Option<&'c Command<'lua>> {
+    let mut tokens = path.split_whitespace();
+    let mut node = get_command(commands, tokens.next()?)?;
+    for token in tokens {
+        node = get_command(node.commands.as_ref()?, token)?;
+    }
+    Some(node)
+}
+
+/// Flattens the command tree into the scripted commands, sorted by their full paths.
+pub fn flatten_commands<'c, 'lua>(
+    commands: &'c HashMap<String, Command<'lua>>,
+) -> Vec<&'c CommandData<'lua>> {
+    fn walk<'c, 'lua>(
+        commands: &'c HashMap<String, Command<'lua>>,
+        out: &mut Vec<&'c CommandData<'lua>>,
+    ) {
+        for command in commands.values() {
+            if let Some(data) = command.data.as_ref() {
+                out.push(data);
+            }
+            if let Some(commands) = command.commands.as_ref() {
+                walk(commands, out);
+            }
+        }
+    }
+
+    let mut flat = Vec::new();
+    walk(commands, &mut flat);
+    flat.sort_by(|a, b| a.id.cmp(&b.id));
+    flat
+}
+
+/// Packs the items into as few messages as possible, each at most `limit` characters long.
+/// Every message starts with the header. Items that don't fit into a message on their own
+/// are put into a separate message as is.
+pub fn pack_messages<S: AsRef<str>>(
+    header: &str,
+    items: &[S],
+    separator: &str,
+    limit: usize,
+) -> Vec<String> {
+    let mut messages = Vec::new();
+    let mut current = String::from(header);
+    let mut is_empty = true;
+
+    for item in items.iter().map(AsRef::as_ref) {
+        let extra = if is_empty { 0 } else { separator.chars().count() };
+        if !is_empty && current.chars().count() + extra + item.chars().count() > limit {
+            messages.push(std::mem::replace(&mut current, String::from(header)));
+            is_empty = true;
+        }
+        if !is_empty {
+            current.push_str(separator);
+        }
+        current.push_str(item);
+        is_empty = false;
+    }
+
+    if !is_empty {
+        messages.push(current);
+    }
+    messages
+}
+
+/// Splits a message into parts of at most `limit` characters. Breaks between words where possible,
+/// and between grapheme clusters otherwise, so that emoji never get cut in half.
+/// Every part but the first starts with the `continuation`.
+pub fn split_message(message: &str, limit: usize, continuation: &str) -> Vec<String> {
+    if message.chars().count() <= limit {
+        return vec![message.to_owned()];
+    }
+    let continuation_length = continuation.chars().count();
+    let room = |parts: usize| match parts {
+        0 => limit,
+        _ => limit.saturating_sub(continuation_length),
+    }
+    .max(1);
+
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut length = 0;
+    for word in message.split_whitespace() {
+        let word_length = word.chars().count();
+        let separator = if current.is_empty() { 0 } else { 1 };
+        if length + separator + word_length <= room(parts.len()) {
+            if separator > 0 {
+                current.push(' ');
+            }
+            current.push_str(word);
+            length += separator + word_length;
+            continue;
+        }
+        if !current.is_empty() {
+            parts.push(std::mem::take(&mut current));
+            length = 0;
+        }
+        if word_length <= room(parts.len()) {
+            current.push_str(word);
+            length = word_length;
+            continue;
+        }
+        // The word doesn't fit into a part of its own
+        for grapheme in word.graphemes(true) {
+            let grapheme_length = grapheme.chars().count();
+            if !current.is_empty() && length + grapheme_length > room(parts.len()) {
+                parts.push(std::mem::take(&mut current));
+                length = 0;
+            }
+            current.push_str(grapheme);
+            length += grapheme_length;
+        }
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(i, part)| match i {
+            0 => part,
+            _ => format!("{}{}", continuation, part),
+        })
+        .collect()
+}
+
+/// Calls the visitor with every scripted command in the tree.
+pub fn visit_commands_mut<'lua, F>(commands: &mut HashMap<String, Command<'lua>>, visitor: &mut F)
+where
+    F: FnMut(&mut CommandData<'lua>),
+{
+    for command in commands.values_mut() {
+        if let Some(data) = command.data.as_mut() {
+            visitor(data);
+        }
+        if let Some(commands) = command.commands.as_mut() {
+            visit_commands_mut(commands, visitor);
+        }
+    }
+}
+
 pub fn reload_command<'a, 'b, 'lua, F>(
     commands: &mut HashMap<String, Command<'lua>>,
     name: &'a str,
@@ -128,13 +433,13 @@ where
     let mut next_commands = commands;
     let mut i = 0;
 
-    while let Some(command) = next_commands.get_mut(tokens[i]) {
+    while let Some(command) = get_command_mut(next_commands, tokens[i]) {
         let commands = command.commands.as_mut();
 
         if i + 1 < tokens.len()
             && commands
                 .as_ref()
-                .map(|c| c.contains_key(tokens[i + 1]))
+                .map(|c| get_command(c, tokens[i + 1]).is_some())
                 .unwrap_or(false)
         {
             next_commands = commands.unwrap();
@@ -155,3 +460,86 @@ where
         name
     )))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescape_tag_reverts_the_escapes() {
+        assert_eq!(unescape_tag(r"hello\sworld"), "hello world");
+        assert_eq!(unescape_tag(r"a\:b"), "a;b");
+        assert_eq!(unescape_tag(r"back\\slash"), r"back\slash");
+        assert_eq!(unescape_tag(r"line\r\nbreak"), "line\r\nbreak");
+    }
+
+    #[test]
+    fn unescape_tag_drops_invalid_escapes() {
+        assert_eq!(unescape_tag(r"trailing\"), "trailing");
+        assert_eq!(unescape_tag(r"\qquote"), "qquote");
+        assert_eq!(unescape_tag("plain"), "plain");
+    }
+
+    #[test]
+    fn split_message_keeps_short_messages() {
+        assert_eq!(split_message("xD", 10, "… "), vec!["xD"]);
+        assert_eq!(split_message("", 10, "… "), vec![""]);
+    }
+
+    #[test]
+    fn split_message_breaks_between_words() {
+        assert_eq!(
+            split_message("aaa bbb ccc", 7, "…"),
+            vec!["aaa bbb", "…ccc"]
+        );
+        assert_eq!(
+            split_message("aaa   bbb ccc ddd", 9, "… "),
+            vec!["aaa bbb", "… ccc ddd"]
+        );
+    }
+
+    #[test]
+    fn split_message_breaks_long_words() {
+        assert_eq!(
+            split_message("abcdefghij", 4, "-"),
+            vec!["abcd", "-efg", "-hij"]
+        );
+    }
+
+    #[test]
+    fn split_message_keeps_graphemes_whole() {
+        let thumbs = "👍🏽";
+        let parts = split_message(&thumbs.repeat(3), 5, "");
+        assert_eq!(parts, vec![thumbs.repeat(2), thumbs.to_owned()]);
+    }
+
+    #[test]
+    fn split_message_respects_the_limit() {
+        let message = "Lorem ipsum dolor sit amet, consectetur adipiscing elit ".repeat(20);
+        let parts = split_message(&message, 50, "… ");
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|part| part.chars().count() <= 50));
+        assert!(parts[1..].iter().all(|part| part.starts_with("… ")));
+    }
+
+    #[test]
+    fn pack_messages_fills_each_message() {
+        assert_eq!(
+            pack_messages("h: ", &["a", "b", "c"], ", ", 8),
+            vec!["h: a, b", "h: c"]
+        );
+        assert_eq!(
+            pack_messages("h: ", &["a", "b", "c"], ", ", 100),
+            vec!["h: a, b, c"]
+        );
+    }
+
+    #[test]
+    fn pack_messages_handles_empty_and_oversized_items() {
+        assert!(pack_messages::<&str>("h: ", &[], ", ", 8).is_empty());
+        assert_eq!(
+            pack_messages("", &["abcdefgh", "i"], " ", 3),
+            vec!["abcdefgh", "i"]
+        );
+    }
+}
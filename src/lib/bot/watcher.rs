@@ -0,0 +1,113 @@
+//! Watches `commands.json` and the command scripts for changes, so that they can be hot reloaded.
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::BackendError;
+
+pub struct ScriptWatcher {
+    watcher: RecommendedWatcher,
+    rx: mpsc::UnboundedReceiver<PathBuf>,
+    /// The watched directories. Watching the directories rather than the files themselves
+    /// survives editors that save by replacing the file.
+    dirs: HashSet<PathBuf>,
+    /// Canonical paths of the watched files, mapped to the paths as written in `commands.json`.
+    files: HashMap<PathBuf, String>,
+}
+
+impl ScriptWatcher {
+    /// Creates a watcher that reports a file once no more writes arrive for `debounce`.
+    pub fn new(debounce: Duration) -> Result<ScriptWatcher, BackendError> {
+        let (notify_tx, notify_rx) = std::sync::mpsc::channel();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let watcher = notify::watcher(notify_tx, debounce)
+            .map_err(|e| BackendError::from(format!("Failed to create a file watcher: {}", e)))?;
+
+        std::thread::Builder::new()
+            .name("script-watcher".to_owned())
+            .spawn(move || {
+                // Exits once the watcher is dropped and the channel closes
+                for event in notify_rx {
+                    let path = match event {
+                        DebouncedEvent::Write(path)
+                        | DebouncedEvent::Create(path)
+                        | DebouncedEvent::Rename(_, path) => path,
+                        DebouncedEvent::Error(e, path) => {
+                            thread_error!("File watcher error at {:?}: {}", path, e);
+                            continue;
+                        }
+                        _ => continue,
+                    };
+                    if tx.send(path).is_err() {
+                        break;
+                    }
+                }
+            })
+            .map_err(|e| BackendError::from(format!("Failed to spawn the watcher thread: {}", e)))?;
+
+        Ok(ScriptWatcher {
+            watcher,
+            rx,
+            dirs: HashSet::new(),
+            files: HashMap::new(),
+        })
+    }
+
+    /// Replaces the set of watched files. A missing file is watched through its directory, so that
+    /// recreating it counts as a change. Files in missing directories are retried on the next call.
+    pub fn watch<I: IntoIterator<Item = String>>(&mut self, files: I) {
+        self.files.clear();
+        for file in files {
+            let canonical = match canonical_path(Path::new(&file)) {
+                Ok(path) => path,
+                Err(e) => {
+                    log::warn!("Not watching `{}` until the next reload: {}", file, e);
+                    continue;
+                }
+            };
+            if let Some(dir) = canonical.parent() {
+                if !self.dirs.contains(dir) {
+                    match self.watcher.watch(dir, RecursiveMode::NonRecursive) {
+                        Ok(()) => {
+                            self.dirs.insert(dir.to_owned());
+                        }
+                        Err(e) => log::error!("Failed to watch {:?}: {}", dir, e),
+                    }
+                }
+            }
+            self.files.insert(canonical, file);
+        }
+        log::info!("Watching {} files for changes", self.files.len());
+    }
+
+    /// Waits for the next change to a watched file, returning its path as written in `commands.json`.
+    pub async fn changed(&mut self) -> Option<String> {
+        loop {
+            let path = self.rx.recv().await?;
+            if let Some(file) = path
+                .canonicalize()
+                .ok()
+                .and_then(|path| self.files.get(&path))
+            {
+                return Some(file.clone());
+            }
+        }
+    }
+}
+
+/// Canonicalizes the path, or for a missing file, the directory it would be created in.
+fn canonical_path(path: &Path) -> std::io::Result<PathBuf> {
+    match path.canonicalize() {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let name = path.file_name().ok_or(e)?;
+            let dir = match path.parent() {
+                Some(dir) if dir != Path::new("") => dir,
+                _ => Path::new("."),
+            };
+            Ok(dir.canonicalize()?.join(name))
+        }
+        result => result,
+    }
+}
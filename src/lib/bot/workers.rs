@@ -0,0 +1,261 @@
+//! A fixed pool of Lua states that run the expensive commands off the event loop.
+//!
+//! Every worker owns a pre-initialized Lua state and a single-threaded runtime, and caches the
+//! compiled scripts it has run. Invocations are queued onto the pool, and rejected once
+//! the queue is full, so that a chat spam can't pile up threads or runtimes.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::command::{self, ScriptArgs};
+use super::config::WorkerConfig;
+use super::invocations::Running;
+use super::outbox::{Destination, Outbox};
+use super::{error_response, interrupted_response, send_in_thread, APIStorage, BotInfo};
+use crate::lua::sandbox::{InstructionBudget, Sandbox};
+use crate::lua::store::Store;
+
+/// An invocation of an expensive command.
+pub struct Job {
+    /// The full path of the command, e.g. `song queue`.
+    pub id: String,
+    /// The path of the command's script.
+    pub path: String,
+    pub sandbox: Sandbox,
+    pub instruction_limit: u64,
+    pub timeout: Option<Duration>,
+    pub reply_to: Destination,
+    pub args: ScriptArgs,
+    pub running: Running,
+}
+
+struct Queued {
+    job: Job,
+    generation: u64,
+}
+
+/// The globals of the worker Lua states.
+#[derive(Clone)]
+pub struct WorkerGlobals {
+    pub store: Store,
+    pub api: APIStorage,
+    pub bot: BotInfo,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+    completed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// A snapshot of the pool's load.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolStats {
+    pub size: usize,
+    pub queue_length: usize,
+    pub queued: usize,
+    pub busy: usize,
+    pub completed: u64,
+    pub rejected: u64,
+}
+
+impl std::fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{} busy, {}/{} queued, {} completed, {} rejected",
+            self.busy, self.size, self.queued, self.queue_length, self.completed, self.rejected
+        )
+    }
+}
+
+pub struct WorkerPool {
+    queue: SyncSender<Queued>,
+    stats: Arc<Stats>,
+    /// Bumped on every reload, so that the workers recompile their cached scripts.
+    generation: AtomicU64,
+    size: usize,
+    queue_length: usize,
+}
+
+impl WorkerPool {
+    /// Spawns the workers. Each of them initializes its own Lua state with the given globals.
+    pub fn start(
+        config: &WorkerConfig,
+        memory_limit: usize,
+        globals: WorkerGlobals,
+        outbox: Outbox,
+    ) -> WorkerPool {
+        let (tx, rx) = mpsc::sync_channel(config.queue_length);
+        let rx = Arc::new(Mutex::new(rx));
+        let stats = Arc::new(Stats::default());
+
+        for index in 0..config.size {
+            let rx = rx.clone();
+            let stats = stats.clone();
+            let globals = globals.clone();
+            let outbox = outbox.clone();
+            std::thread::Builder::new()
+                .name(format!("lua-worker-{}", index))
+                .spawn(move || work(rx, stats, globals, outbox, memory_limit))
+                .expect("Failed to spawn a Lua worker");
+        }
+        log::info!(
+            "Started {} Lua workers with a queue of {}",
+            config.size,
+            config.queue_length
+        );
+
+        WorkerPool {
+            queue: tx,
+            stats,
+            generation: AtomicU64::new(0),
+            size: config.size,
+            queue_length: config.queue_length,
+        }
+    }
+
+    /// Queues the job, handing it back if the queue is full.
+    pub fn submit(&self, job: Job) -> Result<(), Job> {
+        let queued = Queued {
+            job,
+            generation: self.generation.load(Ordering::Relaxed),
+        };
+        // Counted before sending, so that a fast worker can't decrement it first
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        match self.queue.try_send(queued) {
+            Ok(()) => {
+                log::debug!("Queued a job, {}", self.stats());
+                Ok(())
+            }
+            Err(TrySendError::Full(queued)) | Err(TrySendError::Disconnected(queued)) => {
+                self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(queued.job)
+            }
+        }
+    }
+
+    /// Makes the workers recompile the scripts they have cached.
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            size: self.size,
+            queue_length: self.queue_length,
+            queued: self.stats.queued.load(Ordering::Relaxed),
+            busy: self.stats.busy.load(Ordering::Relaxed),
+            completed: self.stats.completed.load(Ordering::Relaxed),
+            rejected: self.stats.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn work(
+    rx: Arc<Mutex<Receiver<Queued>>>,
+    stats: Arc<Stats>,
+    globals: WorkerGlobals,
+    outbox: Outbox,
+    memory_limit: usize,
+) {
+    let lua = mlua::Lua::new();
+    crate::lua::init_globals_with(&lua, globals.store, globals.api, globals.bot);
+    let budget = InstructionBudget::install(&lua, memory_limit);
+    let mut rt = match tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+    {
+        Ok(rt) => rt,
+        Err(e) => {
+            thread_error!("Failed to create a tokio runtime: {:?}", e);
+            return;
+        }
+    };
+    // The compiled scripts, along with the generation they were compiled in
+    let mut cache: HashMap<String, (u64, mlua::Function)> = HashMap::new();
+
+    loop {
+        // The lock is only held while waiting, so the idle workers take turns
+        let next = rx.lock().unwrap().recv();
+        let Queued { job, generation } = match next {
+            Ok(queued) => queued,
+            Err(_) => break,
+        };
+        stats.queued.fetch_sub(1, Ordering::Relaxed);
+        stats.busy.fetch_add(1, Ordering::Relaxed);
+
+        let script = match cache.get(&job.id).cloned() {
+            Some((compiled, script)) if compiled == generation => Some(script),
+            stale => match command::compile(&lua, &job.id, &job.path, &job.sandbox) {
+                Ok(script) => {
+                    cache.insert(job.id.clone(), (generation, script.clone()));
+                    Some(script)
+                }
+                Err(e) => {
+                    thread_error!("Failed to compile the script: {}", e);
+                    // Keep running the previous working version, like the main state does
+                    stale.map(|(_, script)| script)
+                }
+            },
+        };
+
+        if let Some(script) = script {
+            rt.block_on(execute(&budget, &outbox, script, job));
+        } else {
+            send_in_thread(&outbox, &job.reply_to, "WAYTOODANK devs broke something!");
+        }
+
+        stats.busy.fetch_sub(1, Ordering::Relaxed);
+        stats.completed.fetch_add(1, Ordering::Relaxed);
+    }
+    thread_info!("The job queue closed, stopping the worker");
+}
+
+async fn execute(
+    budget: &InstructionBudget,
+    outbox: &Outbox,
+    script: mlua::Function<'_>,
+    job: Job,
+) {
+    let Job {
+        id,
+        instruction_limit,
+        timeout,
+        reply_to,
+        args,
+        running,
+        ..
+    } = job;
+    let response = running
+        .run(
+            timeout,
+            budget.run(
+                instruction_limit,
+                script.call_async::<ScriptArgs, Option<String>>(args),
+            ),
+        )
+        .await;
+    let response = match response {
+        Ok(Ok(Some(resp))) => resp,
+        Ok(Ok(None)) => return,
+        Ok(Err(e)) => {
+            thread_error!("Failed to execute script: {:?}", e);
+            error_response(&e)
+        }
+        Err(interrupted) => {
+            thread_info!("`{}` was interrupted: {:?}", id, interrupted);
+            match interrupted_response(&id, interrupted) {
+                Some(response) => response,
+                None => return,
+            }
+        }
+    };
+    send_in_thread(outbox, &reply_to, response);
+}
@@ -1,7 +1,9 @@
+pub mod sandbox;
+pub mod store;
 mod util;
 
-use crate::bot::{init_api_globals, Bot};
-use mlua::{Lua, ToLua};
+use crate::bot::{init_api_globals, APIStorage, Bot, BotInfo};
+use mlua::{FromLua, Lua, ToLua};
 use std::sync::atomic::{AtomicBool, Ordering};
 use util::init_util_globals;
 
@@ -14,9 +16,11 @@ impl<'lua> ToLua<'lua> for JsonValue {
                 lua.create_sequence_from(a.into_iter().map(JsonValue))?,
             )),
             serde_json::Value::Bool(b) => Ok(mlua::Value::Boolean(b)),
-            serde_json::Value::Number(n) => {
-                Ok(mlua::Value::Number(n.as_f64().expect("good one dude LULW")))
-            }
+            // Integers stay integers, so they don't print as `1.0`
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => Ok(mlua::Value::Integer(i)),
+                None => Ok(mlua::Value::Number(n.as_f64().expect("good one dude LULW"))),
+            },
             serde_json::Value::Object(o) => Ok(mlua::Value::Table(
                 lua.create_table_from(o.into_iter().map(|(k, v)| (k, JsonValue(v))))?,
             )),
@@ -26,6 +30,64 @@ impl<'lua> ToLua<'lua> for JsonValue {
     }
 }
 
+impl<'lua> FromLua<'lua> for JsonValue {
+    fn from_lua(value: mlua::Value<'lua>, lua: &'lua Lua) -> mlua::Result<Self> {
+        Ok(JsonValue(match value {
+            mlua::Value::Nil => serde_json::Value::Null,
+            mlua::Value::Boolean(b) => serde_json::Value::Bool(b),
+            mlua::Value::Integer(i) => serde_json::Value::from(i),
+            mlua::Value::Number(n) => serde_json::Number::from_f64(n)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            mlua::Value::String(s) => serde_json::Value::String(s.to_str()?.to_owned()),
+            mlua::Value::Table(t) => {
+                let pairs = t
+                    .pairs::<mlua::Value, mlua::Value>()
+                    .collect::<mlua::Result<Vec<_>>>()?;
+                // Tables with the keys 1..n are sequences, everything else is an object
+                let is_sequence = !pairs.is_empty()
+                    && pairs.iter().all(|(k, _)| match k {
+                        mlua::Value::Integer(i) => *i >= 1 && *i as usize <= pairs.len(),
+                        _ => false,
+                    });
+                if is_sequence {
+                    let mut items = pairs
+                        .into_iter()
+                        .map(|(k, v)| Ok((i64::from_lua(k, lua)?, JsonValue::from_lua(v, lua)?.0)))
+                        .collect::<mlua::Result<Vec<_>>>()?;
+                    items.sort_by_key(|(k, _)| *k);
+                    serde_json::Value::Array(items.into_iter().map(|(_, v)| v).collect())
+                } else {
+                    let mut object = serde_json::Map::new();
+                    for (k, v) in pairs {
+                        let key = match k {
+                            mlua::Value::String(s) => s.to_str()?.to_owned(),
+                            mlua::Value::Integer(i) => i.to_string(),
+                            mlua::Value::Number(n) => n.to_string(),
+                            other => {
+                                return Err(mlua::Error::FromLuaConversionError {
+                                    from: other.type_name(),
+                                    to: "JSON object key",
+                                    message: None,
+                                })
+                            }
+                        };
+                        object.insert(key, JsonValue::from_lua(v, lua)?.0);
+                    }
+                    serde_json::Value::Object(object)
+                }
+            }
+            other => {
+                return Err(mlua::Error::FromLuaConversionError {
+                    from: other.type_name(),
+                    to: "JSON",
+                    message: None,
+                })
+            }
+        }))
+    }
+}
+
 #[macro_export]
 macro_rules! lua_str {
     ($lua:ident, $str:expr) => {
@@ -49,6 +111,17 @@ pub fn init_globals<'a>(lua: &'a mlua::Lua, bot: &'a Bot<'a>) {
 }
 
 pub(crate) fn init_globals_for_lua<'a>(lua: &'a mlua::Lua, bot: &'a Bot<'a>) {
+    init_globals_with(
+        lua,
+        bot.store.clone(),
+        bot.get_api_storage(),
+        bot.get_bot_info(),
+    );
+}
+
+/// Initializes the globals of a Lua state that lives outside of the bot, e.g. a worker's.
+pub(crate) fn init_globals_with(lua: &mlua::Lua, store: store::Store, api: APIStorage, bot: BotInfo) {
     init_util_globals(lua);
-    init_api_globals(lua, bot.get_api_storage(), bot.get_bot_info());
+    store::register(lua, store);
+    init_api_globals(lua, api, bot);
 }
@@ -0,0 +1,335 @@
+//! Restricted environments and execution limits for the command scripts.
+//!
+//! Every command runs in its own environment table that only exposes the safe parts of the
+//! standard library plus the bot's globals. The dangerous libraries (`io`, `os`, `debug`, `load`, ...)
+//! have to be opted into per command in `commands.json`:
+//!
+//! ```json
+//! "sandbox": { "allow": ["os"], "instruction_limit": 50000000 }
+//! ```
+//!
+//! Likewise, only the commands allowing `"channels"` can join and leave channels with `bot:join`
+//! and `bot:part`.
+//!
+//! Runaway scripts are stopped by an instruction-count hook, and the whole Lua state is capped
+//! by a memory limit. The errors of both limits go through `pcall` uncaught. The memory limit is shared by every script of the state rather than set
+//! per script, so a script hoarding memory makes the allocations of the others fail as well.
+use mlua::{HookTriggers, Lua};
+use serde::Deserialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use super::store;
+use crate::bot::BotInfo;
+
+/// The globals every command can see.
+const SAFE_GLOBALS: &[&str] = &[
+    "_VERSION",
+    "assert",
+    "error",
+    "ipairs",
+    "next",
+    "pairs",
+    "pcall",
+    "print",
+    "rawequal",
+    "rawget",
+    "rawlen",
+    "select",
+    "setmetatable",
+    "tonumber",
+    "tostring",
+    "type",
+    "xpcall",
+    // The bot's own globals
+    "util",
+    "bot",
+    "api",
+];
+
+/// The libraries every command gets its own copy of, so that one script can't patch them for others.
+const SAFE_LIBRARIES: &[&str] = &["string", "table", "math", "utf8", "coroutine"];
+
+/// The globals a command has to opt into.
+pub const RESTRICTED_GLOBALS: &[&str] = &[
+    "io",
+    "os",
+    "debug",
+    "load",
+    "loadfile",
+    "dofile",
+    "require",
+    "package",
+    "collectgarbage",
+    // They reach the metatables shared by every script, e.g. the one of the strings
+    "getmetatable",
+    "rawset",
+];
+
+/// The opt-in that lets a command's `bot` join and leave channels.
+const MANAGE_CHANNELS: &str = "channels";
+
+/// How often the instruction hook fires.
+const HOOK_INTERVAL: u32 = 1000;
+const INSTRUCTION_LIMIT_ERROR: &str = "instruction limit exceeded";
+
+/// Wraps the functions catching errors, so that the sandbox limits can't be caught:
+/// the errors of the instruction limit and of the memory limit are raised again.
+const PROTECTED_CALLS: &str = r#"
+local pcall, xpcall, resume, error, tostring, find, limit_error = ...
+local function is_fatal(e)
+    e = tostring(e)
+    return find(e, limit_error, 1, true) ~= nil or find(e, "not enough memory", 1, true) ~= nil
+end
+local function check(ok, ...)
+    if not ok and is_fatal((...)) then
+        error((...), 0)
+    end
+    return ok, ...
+end
+return function(f, ...)
+    return check(pcall(f, ...))
+end, function(f, handler, ...)
+    return check(xpcall(f, function(e)
+        if is_fatal(e) then
+            return e
+        end
+        return handler(e)
+    end, ...))
+end, function(co, ...)
+    return check(resume(co, ...))
+end
+"#;
+
+/// The sandbox settings of a command, as declared in `commands.json`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Sandbox {
+    /// The restricted globals the command may use, e.g. `["os", "io"]`.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Overrides the global instruction limit for this command.
+    pub instruction_limit: Option<u64>,
+}
+
+/// Creates the global environment of a command. Reads fall through to the allowed globals only,
+/// while writes stay local to the command, so scripts can't clobber each other's globals.
+/// The `store` global is scoped to the command.
+pub fn command_env<'lua>(
+    lua: &'lua Lua,
+    command: &str,
+    sandbox: &Sandbox,
+) -> mlua::Result<mlua::Table<'lua>> {
+    let env = lua.create_table()?;
+    let globals = lua.globals();
+
+    for name in SAFE_LIBRARIES {
+        if let Some(library) = globals.get::<_, Option<mlua::Table>>(*name)? {
+            let copy = lua.create_table()?;
+            for pair in library.pairs::<mlua::Value, mlua::Value>() {
+                let (k, v) = pair?;
+                copy.set(k, v)?;
+            }
+            env.set(*name, copy)?;
+        }
+    }
+    env.set("_G", env.clone())?;
+
+    let (pcall, xpcall, resume) = protected_calls(lua)?;
+    env.set("pcall", pcall)?;
+    env.set("xpcall", xpcall)?;
+    if let Some(coroutine) = env.get::<_, Option<mlua::Table>>("coroutine")? {
+        coroutine.set("resume", resume)?;
+    }
+
+    // The bot's globals are set after the commands are loaded, so they're looked up lazily
+    let manage_channels = sandbox.allow.iter().any(|name| name == MANAGE_CHANNELS);
+    let owner = command.to_owned();
+    let allowed = SAFE_GLOBALS
+        .iter()
+        .map(|s| (*s).to_owned())
+        .chain(
+            sandbox
+                .allow
+                .iter()
+                .filter(|name| RESTRICTED_GLOBALS.contains(&name.as_str()))
+                .cloned(),
+        )
+        .collect::<std::collections::HashSet<_>>();
+    let index = lua.create_function(move |lua, (env, key): (mlua::Table, mlua::Value)| {
+        match key {
+            // Every command gets its own `bot`, owning what it schedules and with the privileges
+            // it opted into
+            mlua::Value::String(ref name) if name.to_str()? == "bot" => {
+                let bot = match lua.globals().get::<_, Option<BotInfo>>("bot")? {
+                    Some(bot) => lua.create_userdata(bot.for_command(&owner, manage_channels))?,
+                    None => return Ok(mlua::Nil),
+                };
+                env.raw_set("bot", bot.clone())?;
+                Ok(mlua::Value::UserData(bot))
+            }
+            mlua::Value::String(ref name) if allowed.contains(name.to_str()?) => {
+                lua.globals().get::<_, mlua::Value>(name.clone())
+            }
+            _ => Ok(mlua::Nil),
+        }
+    })?;
+    let meta = lua.create_table()?;
+    meta.set("__index", index)?;
+    env.set_metatable(Some(meta));
+
+    if let Some(store) = store::root(lua)? {
+        env.set("store", store.scoped(command))?;
+    }
+    Ok(env)
+}
+
+/// Creates the `pcall`, `xpcall` and `coroutine.resume` of a command, which don't catch the
+/// errors of the sandbox limits.
+fn protected_calls(lua: &Lua) -> mlua::Result<(mlua::Function, mlua::Function, mlua::Function)> {
+    let globals = lua.globals();
+    let coroutine = globals.get::<_, mlua::Table>("coroutine")?;
+    let string = globals.get::<_, mlua::Table>("string")?;
+    lua.load(PROTECTED_CALLS)
+        .set_name("sandbox")?
+        .into_function()?
+        .call((
+            globals.get::<_, mlua::Function>("pcall")?,
+            globals.get::<_, mlua::Function>("xpcall")?,
+            coroutine.get::<_, mlua::Function>("resume")?,
+            globals.get::<_, mlua::Function>("error")?,
+            globals.get::<_, mlua::Function>("tostring")?,
+            string.get::<_, mlua::Function>("find")?,
+            INSTRUCTION_LIMIT_ERROR,
+        ))
+}
+
+/// Counts the instructions executed by the scripts of a Lua state.
+///
+/// Every budgeted script keeps its own count across its yields, so the limit applies to all
+/// the instructions of an invocation and `coroutine.yield()` can't be used to reset it.
+#[derive(Debug, Clone)]
+pub struct InstructionBudget {
+    used: Arc<AtomicU64>,
+    limit: Arc<AtomicU64>,
+}
+
+impl InstructionBudget {
+    /// Installs the instruction hook and the memory limit (in bytes, 0 = unlimited) on the Lua state.
+    pub fn install(lua: &Lua, memory_limit: usize) -> InstructionBudget {
+        let budget = InstructionBudget {
+            used: Arc::new(AtomicU64::new(0)),
+            limit: Arc::new(AtomicU64::new(u64::MAX)),
+        };
+
+        let (used, limit) = (budget.used.clone(), budget.limit.clone());
+        lua.set_hook(
+            HookTriggers {
+                every_nth_instruction: Some(HOOK_INTERVAL),
+                ..Default::default()
+            },
+            move |_, _| {
+                let used = used.fetch_add(HOOK_INTERVAL as u64, Ordering::Relaxed);
+                if used + HOOK_INTERVAL as u64 > limit.load(Ordering::Relaxed) {
+                    Err(mlua::Error::RuntimeError(INSTRUCTION_LIMIT_ERROR.to_owned()))
+                } else {
+                    Ok(())
+                }
+            },
+        );
+
+        if memory_limit > 0 {
+            if let Err(e) = lua.set_memory_limit(memory_limit) {
+                log::error!("Failed to set the Lua memory limit: {}", e);
+            }
+        }
+
+        budget
+    }
+
+    /// Runs the script future with the given instruction limit.
+    pub fn run<F: Future>(&self, limit: u64, future: F) -> Budgeted<F> {
+        Budgeted {
+            budget: self.clone(),
+            limit,
+            used: 0,
+            inner: Box::pin(future),
+        }
+    }
+}
+
+/// A script future that restores its instruction count whenever it's resumed.
+pub struct Budgeted<F: Future> {
+    budget: InstructionBudget,
+    limit: u64,
+    /// The instructions executed by the previous polls.
+    used: u64,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Budgeted<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.budget.used.store(self.used, Ordering::Relaxed);
+        self.budget.limit.store(self.limit, Ordering::Relaxed);
+        let result = self.inner.as_mut().poll(cx);
+        self.budget.limit.store(u64::MAX, Ordering::Relaxed);
+        self.used = self.budget.used.load(Ordering::Relaxed);
+        result
+    }
+}
+
+/// Describes the errors caused by the sandbox limits in a user-friendly way.
+pub fn describe_error(error: &mlua::Error) -> Option<&'static str> {
+    match error {
+        mlua::Error::MemoryError(_) => Some("the script ran out of memory"),
+        mlua::Error::CallbackError { cause, .. } => describe_error(cause),
+        e if e.to_string().contains(INSTRUCTION_LIMIT_ERROR) => Some("the script ran for too long"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_limited(source: &str) -> mlua::Result<()> {
+        let lua = Lua::new();
+        let budget = InstructionBudget::install(&lua, 0);
+        let env = command_env(&lua, "test", &Sandbox::default())?;
+        let script = lua.load(source).set_environment(env)?.into_function()?;
+        futures::executor::block_on(budget.run(100_000, script.call_async::<_, ()>(())))
+    }
+
+    #[test]
+    fn instruction_limit_stops_loops() {
+        let error = run_limited("while true do end").unwrap_err();
+        assert_eq!(describe_error(&error), Some("the script ran for too long"));
+    }
+
+    #[test]
+    fn instruction_limit_cant_be_caught() {
+        for source in &[
+            "while true do pcall(function() while true do end end) end",
+            "while true do xpcall(function() while true do end end, function() end) end",
+            "while true do coroutine.resume(coroutine.create(function() while true do end end)) end",
+        ] {
+            let error = run_limited(source).unwrap_err();
+            assert_eq!(describe_error(&error), Some("the script ran for too long"), "{}", source);
+        }
+    }
+
+    #[test]
+    fn protected_calls_still_catch_script_errors() {
+        let source = r#"
+            assert(not pcall(error, "oops"))
+            local ok, e = xpcall(error, function(e) return "handled " .. e end, "oops", 0)
+            assert(not ok and e == "handled oops")
+            assert(not coroutine.resume(coroutine.create(error)))
+        "#;
+        assert!(run_limited(source).is_ok());
+    }
+}
@@ -0,0 +1,259 @@
+//! Persistent key-value storage for the scripts, backed by SQLite.
+//!
+//! Every command gets a `store` global scoped to the command's path, so `store:set("count", 1)`
+//! in `song queue` doesn't clash with `store:set("count", 1)` in `ping`. The timers and the hooks
+//! are scoped to ids commands don't use, e.g. `timer:hydrate`. Scripts may narrow
+//! the scope further with `store:channel(args.channel)`. Values are stored as JSON, and setting
+//! a key to `nil` deletes it.
+
+use mlua::{FromLua, Lua, ToLua, UserData, UserDataMethods};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::sync::{Arc, Mutex};
+
+use super::JsonValue;
+use crate::bot::config::channel_key;
+use crate::BackendError;
+
+const REGISTRY_KEY: &str = "anikibot.store";
+
+#[derive(Clone)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+    namespace: String,
+}
+
+impl Store {
+    /// Opens (or creates) the database at the given path.
+    pub fn open(path: &str) -> Result<Store, BackendError> {
+        let conn = Connection::open(path).map_err(|e| {
+            BackendError::from(format!("Failed to open the store at {}: {}", path, e))
+        })?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS kv (
+                namespace TEXT NOT NULL,
+                key       TEXT NOT NULL,
+                value     TEXT NOT NULL,
+                PRIMARY KEY (namespace, key)
+            )",
+            params![],
+        )
+        .map_err(to_backend_error)?;
+        Ok(Store {
+            conn: Arc::new(Mutex::new(conn)),
+            namespace: String::new(),
+        })
+    }
+
+    /// Returns a store whose keys live in a sub-namespace of this one.
+    pub fn scoped(&self, namespace: &str) -> Store {
+        Store {
+            conn: self.conn.clone(),
+            namespace: if self.namespace.is_empty() {
+                namespace.to_owned()
+            } else {
+                format!("{}/{}", self.namespace, namespace)
+            },
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<serde_json::Value>, BackendError> {
+        let conn = self.conn.lock().unwrap();
+        let value: Option<String> = conn
+            .query_row(
+                "SELECT value FROM kv WHERE namespace = ?1 AND key = ?2",
+                params![self.namespace, key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(to_backend_error)?;
+        value.map(|v| parse_value(&v)).transpose()
+    }
+
+    pub fn set(&self, key: &str, value: &serde_json::Value) -> Result<(), BackendError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO kv (namespace, key, value) VALUES (?1, ?2, ?3)",
+            params![self.namespace, key, value.to_string()],
+        )
+        .map(|_| ())
+        .map_err(to_backend_error)
+    }
+
+    /// Deletes the key, returning whether it existed.
+    pub fn delete(&self, key: &str) -> Result<bool, BackendError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM kv WHERE namespace = ?1 AND key = ?2",
+            params![self.namespace, key],
+        )
+        .map(|n| n > 0)
+        .map_err(to_backend_error)
+    }
+
+    /// Adds `by` to the numeric value at the key (missing keys count as 0) and returns the result.
+    /// The result stays an integer when both numbers are integers.
+    pub fn increment(
+        &self,
+        key: &str,
+        by: serde_json::Number,
+    ) -> Result<serde_json::Number, BackendError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(to_backend_error)?;
+        let current: Option<String> = tx
+            .query_row(
+                "SELECT value FROM kv WHERE namespace = ?1 AND key = ?2",
+                params![self.namespace, key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(to_backend_error)?;
+        let current = match current.map(|v| parse_value(&v)).transpose()? {
+            Some(serde_json::Value::Number(n)) => n,
+            Some(other) => {
+                return Err(BackendError::from(format!(
+                    "Cannot increment `{}`: {} is not a number",
+                    key, other
+                )))
+            }
+            None => serde_json::Number::from(0),
+        };
+        let next = match (current.as_i64(), by.as_i64()) {
+            (Some(current), Some(by)) => current.checked_add(by).map(serde_json::Number::from),
+            _ => None,
+        };
+        // Floats, and the integers that would overflow
+        let next = match next {
+            Some(next) => next,
+            None => {
+                let sum = current.as_f64().unwrap_or(0.0) + by.as_f64().unwrap_or(0.0);
+                serde_json::Number::from_f64(sum)
+                    .ok_or_else(|| BackendError::from(format!("Cannot store {} as JSON", sum)))?
+            }
+        };
+        let value = serde_json::Value::Number(next.clone());
+        tx.execute(
+            "INSERT OR REPLACE INTO kv (namespace, key, value) VALUES (?1, ?2, ?3)",
+            params![self.namespace, key, value.to_string()],
+        )
+        .map_err(to_backend_error)?;
+        tx.commit().map_err(to_backend_error)?;
+        Ok(next)
+    }
+
+    /// Lists the keys starting with the prefix, along with their values, sorted by key.
+    pub fn list(&self, prefix: &str) -> Result<Vec<(String, serde_json::Value)>, BackendError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT key, value FROM kv
+                 WHERE namespace = ?1 AND substr(key, 1, length(?2)) = ?2
+                 ORDER BY key",
+            )
+            .map_err(to_backend_error)?;
+        let rows = stmt
+            .query_map(params![self.namespace, prefix], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(to_backend_error)?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (key, value) = row.map_err(to_backend_error)?;
+            entries.push((key, parse_value(&value)?));
+        }
+        Ok(entries)
+    }
+}
+
+fn parse_value(value: &str) -> Result<serde_json::Value, BackendError> {
+    serde_json::from_str(value)
+        .map_err(|e| BackendError::from(format!("Corrupted value in the store: {}", e)))
+}
+
+fn to_backend_error(e: rusqlite::Error) -> BackendError {
+    BackendError::from(format!("Store error: {}", e))
+}
+
+/// Makes the root store available to the command environments of the given Lua state.
+pub fn register(lua: &Lua, store: Store) {
+    if let Err(e) = lua.set_named_registry_value(REGISTRY_KEY, store) {
+        log::error!("Failed to register the store: {}", e);
+    }
+}
+
+/// Returns the root store of the Lua state, if one was registered.
+pub(crate) fn root(lua: &Lua) -> mlua::Result<Option<Store>> {
+    lua.named_registry_value::<_, Option<Store>>(REGISTRY_KEY)
+}
+
+fn to_lua_result<'lua, T: ToLua<'lua>>(
+    lua: &'lua Lua,
+    result: Result<T, BackendError>,
+) -> mlua::Result<(mlua::Value<'lua>, mlua::Value<'lua>)> {
+    Ok(match result {
+        Ok(value) => (value.to_lua(lua)?, mlua::Nil),
+        Err(e) => (
+            mlua::Nil,
+            mlua::Value::String(lua.create_string(&e.to_string())?),
+        ),
+    })
+}
+
+impl UserData for Store {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("get", |lua, instance, key: String| {
+            to_lua_result(lua, instance.get(&key).map(|v| v.map(JsonValue)))
+        });
+        methods.add_method(
+            "set",
+            |lua, instance, (key, value): (String, mlua::Value)| {
+                if let mlua::Value::Nil = value {
+                    return to_lua_result(lua, instance.delete(&key).map(|_| true));
+                }
+                let value = JsonValue::from_lua(value, lua)?;
+                to_lua_result(lua, instance.set(&key, &value.0).map(|_| true))
+            },
+        );
+        methods.add_method("delete", |lua, instance, key: String| {
+            to_lua_result(lua, instance.delete(&key))
+        });
+        methods.add_method(
+            "increment",
+            |lua, instance, (key, by): (String, Option<mlua::Value>)| {
+                let by = match by {
+                    None | Some(mlua::Value::Nil) => serde_json::Number::from(1),
+                    Some(mlua::Value::Integer(by)) => serde_json::Number::from(by),
+                    Some(mlua::Value::Number(by)) if by.is_finite() => {
+                        serde_json::Number::from_f64(by).expect("finite numbers are valid JSON")
+                    }
+                    Some(other) => {
+                        return Err(mlua::Error::FromLuaConversionError {
+                            from: other.type_name(),
+                            to: "finite number",
+                            message: None,
+                        })
+                    }
+                };
+                to_lua_result(
+                    lua,
+                    instance
+                        .increment(&key, by)
+                        .map(|n| JsonValue(serde_json::Value::Number(n))),
+                )
+            },
+        );
+        methods.add_method("list", |lua, instance, prefix: Option<String>| {
+            let entries = instance.list(prefix.as_deref().unwrap_or(""));
+            let table = match entries {
+                Ok(entries) => Ok(lua.create_table_from(
+                    entries.into_iter().map(|(k, v)| (k, JsonValue(v))),
+                )?),
+                Err(e) => Err(e),
+            };
+            to_lua_result(lua, table)
+        });
+        methods.add_method("channel", |_, instance, channel: String| {
+            Ok(instance.scoped(&format!("#{}", channel_key(&channel))))
+        });
+    }
+}
@@ -1,4 +1,8 @@
+use crate::bot::command::context;
+use crate::bot::emotes;
+use crate::bot::util::{split_message, MAX_MESSAGE_LENGTH};
 use mlua::{Lua, UserData, UserDataMethods, Variadic};
+use std::collections::HashSet;
 use std::time::Duration;
 
 /// Initializes utility globals
@@ -12,20 +16,63 @@ pub fn init_util_globals(lua: &Lua) {
 pub struct Util {}
 impl UserData for Util {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("get_args", |lua, _, va: Variadic<String>| {
+        methods.add_method("get_args", |lua, _, va: Variadic<mlua::Value>| {
             let table = lua.create_table()?;
+            let mut va = va.into_iter();
 
-            table.set("length", va.len() - 2)?;
-            table.set("channel", va[0].clone())?;
-            table.set("user", va[1].clone())?;
+            table.set("channel", va.next().unwrap_or(mlua::Nil))?;
+            table.set("user", va.next().unwrap_or(mlua::Nil))?;
 
-            for i in 0..(va.len() - 2) {
-                table.set(i, va[i + 2].clone())?;
+            // Words are positional arguments, tables (parsed arguments) are merged as is,
+            // except for the trailing `ctx` table
+            let mut length = 0;
+            let mut tables = Vec::new();
+            for value in va {
+                match value {
+                    mlua::Value::Table(t) if is_ctx(&t) => table.set("ctx", t)?,
+                    mlua::Value::Table(t) => tables.push(t),
+                    value => {
+                        table.set(length, value)?;
+                        length += 1;
+                    }
+                }
+            }
+            table.set("length", length)?;
+            for t in tables {
+                for pair in t.pairs::<mlua::Value, mlua::Value>() {
+                    let (k, v) = pair?;
+                    table.set(k, v)?;
+                }
             }
 
             Ok(table)
         });
         methods.add_method("len", |_, _, table: mlua::Table| Ok(table.len()));
+        // Takes the emotes to strip as names or as the `ctx.emotes` tables
+        methods.add_method(
+            "strip_emotes",
+            |_, _, (text, names): (String, Vec<mlua::Value>)| {
+                let names = names
+                    .into_iter()
+                    .filter_map(|name| match name {
+                        mlua::Value::String(name) => name.to_str().ok().map(str::to_owned),
+                        mlua::Value::Table(emote) => emote.get::<_, String>("name").ok(),
+                        _ => None,
+                    })
+                    .collect::<HashSet<_>>();
+                Ok(emotes::strip(&text, &names))
+            },
+        );
+        methods.add_method(
+            "split_message",
+            |lua, _, (message, limit, continuation): (String, Option<usize>, Option<String>)| {
+                lua.create_sequence_from(split_message(
+                    &message,
+                    limit.unwrap_or(MAX_MESSAGE_LENGTH).max(1),
+                    continuation.as_deref().unwrap_or(""),
+                ))
+            },
+        );
         methods.add_method("info", |_, _, va: Variadic<mlua::Value<'lua>>| {
             log::info!(
                 "[ LUA ] {}",
@@ -74,6 +121,11 @@ impl UserData for Util {
     }
 }
 
+/// Whether the table is the `ctx` table passed after the arguments.
+fn is_ctx(table: &mlua::Table) -> bool {
+    context::caller(table).is_some()
+}
+
 fn lua_value_to_string<'lua>(v: &mlua::Value<'lua>, is_top_level: bool) -> String {
     match v {
         mlua::Value::Nil => "nil".to_owned(),
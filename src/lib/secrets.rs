@@ -7,6 +7,9 @@ pub struct Secrets {
     pub oauth_token: String,
     pub stream_elements_jwt_token: Option<String>,
     pub youtube_api_key: Option<String>,
+    /// The client id of the app the oauth token was generated for, for the Helix API
+    /// (the live checks and the whispers).
+    pub twitch_client_id: Option<String>,
 }
 
 impl Secrets {
@@ -26,6 +29,9 @@ impl Into<UserConfig> for Secrets {
         twitchchat::UserConfig::builder()
             .name(&self.name)
             .token(&self.oauth_token)
+            // The permission levels need the badges from the tags, membership is for JOIN/PART
+            // and commands for the USERSTATE/NOTICE messages
+            .enable_all_capabilities()
             .build()
             .unwrap()
     }
@@ -13,11 +13,24 @@ pub mod stats;
 #[derive(Debug, Clone)]
 pub struct ConsumerStreamElementsAPI {
     tx: RequestSender,
+    /// The StreamElements id of the channel the methods act on, the API user's channel if `None`.
+    channel_id: Option<String>,
 }
 
 impl ConsumerStreamElementsAPI {
     pub fn new(tx: RequestSender) -> Self {
-        Self { tx }
+        Self {
+            tx,
+            channel_id: None,
+        }
+    }
+
+    /// Makes the song request and stats methods act on the given StreamElements channel.
+    pub fn for_channel<S: Into<String>>(self, channel_id: S) -> Self {
+        Self {
+            channel_id: Some(channel_id.into()),
+            ..self
+        }
     }
 
     #[must_use = "Calling channels() does nothing"]
@@ -27,12 +40,12 @@ impl ConsumerStreamElementsAPI {
 
     #[must_use = "Calling song_requests() does nothing"]
     pub fn song_requests(&self) -> SongRequests {
-        SongRequests::new(self.tx.clone())
+        SongRequests::new(self.tx.clone()).with_channel(self.channel_id.clone())
     }
 
     #[must_use = "Calling stats() does nothing"]
     pub fn stats(&self) -> Stats {
-        Stats::new(self.tx.clone())
+        Stats::new(self.tx.clone()).with_channel(self.channel_id.clone())
     }
 }
 
@@ -43,6 +56,7 @@ impl UserData for ConsumerStreamElementsAPI {
             Ok(instance.song_requests())
         });
         methods.add_method("stats", |_, instance, ()| Ok(instance.stats()));
+        methods.add_method("channel_id", |_, instance, ()| Ok(instance.channel_id.clone()));
     }
 }
 
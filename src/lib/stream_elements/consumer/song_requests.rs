@@ -9,17 +9,30 @@ use mlua::{UserData, UserDataMethods};
 #[derive(Clone)]
 pub struct SongRequests {
     tx: RequestSender,
+    channel_id: Option<String>,
 }
 
 impl SongRequests {
     /// Creates a new `SongRequests` object.
     pub fn new(tx: RequestSender) -> Self {
-        Self { tx }
+        Self {
+            tx,
+            channel_id: None,
+        }
     }
 
-    /// Retrieves the song request settings of the API user.
+    /// Makes `get_settings`, `queue` and `queue_many` act on the given channel instead of
+    /// the API user's.
+    pub fn with_channel(self, channel_id: Option<String>) -> Self {
+        Self { channel_id, ..self }
+    }
+
+    /// Retrieves the song request settings of the API user, or the public settings of the bound channel.
     pub async fn get_settings(&self) -> APIResponse {
-        api_send!(self, APIRequestKind::SongReq_Settings)
+        match &self.channel_id {
+            Some(channel_id) => self.get_public_settings(channel_id.clone()).await,
+            None => api_send!(self, APIRequestKind::SongReq_Settings),
+        }
     }
 
     /// Retrieves the song request settings for the given `channel_id`.
@@ -57,8 +70,13 @@ impl SongRequests {
         )
     }
 
-    /// Queues the given song in the API user's channel.
+    /// Queues the given song in the bound channel or the API user's channel.
     pub async fn queue<S: Into<String>>(&self, song_url: S) -> APIResponse {
+        if let Some(channel_id) = &self.channel_id {
+            return self
+                .queue_song_in_channel(channel_id.clone(), song_url.into())
+                .await;
+        }
         api_send!(
             self,
             APIRequestKind::SongReq_QueueSong {
@@ -82,8 +100,13 @@ impl SongRequests {
         )
     }
 
-    /// Queues the given songs in the API user's channel.
+    /// Queues the given songs in the bound channel or the API user's channel.
     pub async fn queue_many(&self, song_urls: Vec<String>) -> APIResponse {
+        if let Some(channel_id) = &self.channel_id {
+            return self
+                .queue_many_in_channel(channel_id.clone(), song_urls)
+                .await;
+        }
         api_send!(self, APIRequestKind::SongReq_QueueMany { song_urls })
     }
 }
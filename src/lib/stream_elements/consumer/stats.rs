@@ -13,6 +13,7 @@ use crate::stream_elements::stats::StatsSettings;
 pub struct Stats {
     tx: RequestSender,
     settings: StatsSettings,
+    channel_id: Option<String>,
 }
 impl Stats {
     /// Creates a new `Stats` object.
@@ -20,16 +21,25 @@ impl Stats {
         Self {
             tx,
             settings: StatsSettings::default(),
+            channel_id: None,
         }
     }
 
+    /// Makes `my_stats` retrieve the stats of the given channel instead of the API user's.
+    pub fn with_channel(self, channel_id: Option<String>) -> Self {
+        Self { channel_id, ..self }
+    }
+
     /// Stores the given settings.
     pub fn with_settings(self, settings: StatsSettings) -> Self {
         Self { settings, ..self }
     }
 
-    /// Retrieves the stats of the API user's channel.
+    /// Retrieves the stats of the bound channel or the API user's channel.
     pub async fn my_stats(&self) -> APIResponse {
+        if let Some(channel_id) = &self.channel_id {
+            return self.stats_for_channel(channel_id.clone()).await;
+        }
         api_send!(
             self,
             APIRequestKind::Stats_MyStats {
@@ -28,7 +28,8 @@ async fn main() {
     let dispatcher = Dispatcher::new();
     let (runner, control) = Runner::new(
         dispatcher.clone(),
-        RateLimit::full(1, std::time::Duration::from_secs(1)),
+        // The per-channel limits are kept by the bot's outbox, this only guards the connection
+        RateLimit::full(100, std::time::Duration::from_secs(30)),
     );
 
     let secrets = Secrets::get();
@@ -56,6 +57,9 @@ async fn main() {
             thread_handles.push(handle);
             builder = builder.add_youtube_api(api);
         }
+        if let Some(ref id) = secrets.twitch_client_id {
+            builder = builder.add_helix_credentials(id.to_owned(), secrets.oauth_token.clone());
+        }
 
         builder.build(&lua)
     };